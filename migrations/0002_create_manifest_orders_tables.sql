@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS manifest_packages (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    version TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS manifest_orders (
+    id UUID PRIMARY KEY,
+    package_id UUID NOT NULL REFERENCES manifest_packages (id) ON DELETE CASCADE,
+    item TEXT NOT NULL,
+    quantity BIGINT NOT NULL,
+    unit TEXT,
+    price DOUBLE PRECISION,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS manifest_orders_item_idx ON manifest_orders (item);
+CREATE INDEX IF NOT EXISTS manifest_orders_created_at_idx ON manifest_orders (created_at);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS bucket_audit_log (
+    id UUID PRIMARY KEY,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    previous JSONB NOT NULL,
+    current JSONB NOT NULL
+);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS milk_ledger (
+    id BIGSERIAL PRIMARY KEY,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    kind TEXT NOT NULL CHECK (kind IN ('withdrawal', 'deposit')),
+    client TEXT NOT NULL,
+    -- The milk as handed out or poured in.
+    amount DOUBLE PRECISION NOT NULL,
+    unit TEXT NOT NULL,
+    -- Signed change to the stock: `amount` in millilitres, negative for
+    -- withdrawals.
+    millilitres DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS milk_ledger_recorded_at_idx ON milk_ledger (recorded_at);
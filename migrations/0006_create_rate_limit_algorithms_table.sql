@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS rate_limit_algorithms (
+    scope TEXT PRIMARY KEY,
+    algorithm JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,38 @@
+//! Checks for endpoints guarded by a shared secret token.
+
+use std::iter::zip;
+
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+
+/// Whether `headers` carry `Authorization: Bearer <token>`. The comparison
+/// takes the same time wherever the provided token first differs.
+pub fn bearer_matches(headers: &HeaderMap, token: &str) -> bool {
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    provided.is_some_and(|provided| {
+        provided.len() == token.len()
+            && zip(provided.bytes(), token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(authorization: &str) -> bool {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        bearer_matches(&headers, "hunter2")
+    }
+
+    #[test]
+    fn only_the_exact_bearer_token_matches() {
+        assert!(matches("Bearer hunter2"));
+        assert!(!matches("Bearer hunter3"));
+        assert!(!matches("Bearer hunter"));
+        assert!(!matches("Basic hunter2"));
+        assert!(!bearer_matches(&HeaderMap::new(), "hunter2"));
+    }
+}
@@ -1,30 +1,190 @@
 use std::{
-    net::{Ipv4Addr, Ipv6Addr},
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     ops::BitXor,
+    str::FromStr,
 };
 
-use axum::extract::Query;
-use serde::Deserialize;
+use axum::{
+    body::{Body, BodyDataStream},
+    extract::{Query, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::iter::zip;
 
+use crate::{auth::bearer_matches, AppState};
+
+fn add_octets<const N: usize>(from: [u8; N], key: [u8; N]) -> [u8; N] {
+    zip(from, key)
+        .map(|(from, key)| from.overflowing_add(key).0)
+        .collect::<Vec<u8>>()
+        .try_into()
+        .unwrap()
+}
+
+fn sub_octets<const N: usize>(from: [u8; N], to: [u8; N]) -> [u8; N] {
+    zip(from, to)
+        .map(|(from, to)| to.overflowing_sub(from).0)
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap()
+}
+
+fn xor_octets<const N: usize>(a: [u8; N], b: [u8; N]) -> [u8; N] {
+    zip(a, b)
+        .map(|(a, b)| a.bitxor(b))
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap()
+}
+
+fn dest_v4_addr(from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(add_octets(from.octets(), key.octets()))
+}
+
+fn key_v4_addr(from: Ipv4Addr, to: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(sub_octets(from.octets(), to.octets()))
+}
+
+fn dest_v6_addr(from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+    Ipv6Addr::from(xor_octets(from.octets(), key.octets()))
+}
+
+fn key_v6_addr(from: Ipv6Addr, to: Ipv6Addr) -> Ipv6Addr {
+    Ipv6Addr::from(xor_octets(to.octets(), from.octets()))
+}
+
+/// How a resulting address is written back to the client.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Int,
+    Hex,
+    Binary,
+    Expanded,
+    ReverseDns,
+    /// Inputs and output, each in every other representation.
+    Json,
+}
+
+impl OutputFormat {
+    fn format(self, addr: IpAddr) -> String {
+        let (value, bits) = match addr {
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => (u128::from(v6), 128),
+        };
+        match self {
+            Self::Text | Self::Json => addr.to_string(),
+            Self::Int => value.to_string(),
+            Self::Hex => format!("0x{value:0width$x}", width = bits / 4),
+            Self::Binary => format!("{value:0bits$b}"),
+            Self::Expanded => match addr {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => v6
+                    .segments()
+                    .iter()
+                    .map(|segment| format!("{segment:04x}"))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            },
+            Self::ReverseDns => match addr {
+                IpAddr::V4(v4) => {
+                    let octets: Vec<_> = v4.octets().iter().rev().map(u8::to_string).collect();
+                    format!("{}.in-addr.arpa", octets.join("."))
+                }
+                IpAddr::V6(_) => {
+                    let nibbles: Vec<_> = format!("{value:032x}")
+                        .chars()
+                        .rev()
+                        .map(String::from)
+                        .collect();
+                    format!("{}.ip6.arpa", nibbles.join("."))
+                }
+            },
+        }
+    }
+
+    fn respond(self, inputs: &[(&'static str, IpAddr)], output: IpAddr) -> Response {
+        let Self::Json = self else {
+            return (StatusCode::OK, self.format(output)).into_response();
+        };
+        let report = FormatReport {
+            input: inputs
+                .iter()
+                .map(|&(name, addr)| (name, Representations::new(addr)))
+                .collect(),
+            output: Representations::new(output),
+        };
+        (
+            [(CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&report).unwrap(),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OutputParams {
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+/// Every representation of a single address. `int` is a string since IPv6
+/// addresses overflow JSON numbers.
+#[derive(Serialize)]
+struct Representations {
+    text: String,
+    int: String,
+    hex: String,
+    binary: String,
+    expanded: String,
+    reverse_dns: String,
+}
+
+impl Representations {
+    fn new(addr: IpAddr) -> Self {
+        Self {
+            text: OutputFormat::Text.format(addr),
+            int: OutputFormat::Int.format(addr),
+            hex: OutputFormat::Hex.format(addr),
+            binary: OutputFormat::Binary.format(addr),
+            expanded: OutputFormat::Expanded.format(addr),
+            reverse_dns: OutputFormat::ReverseDns.format(addr),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FormatReport {
+    input: BTreeMap<&'static str, Representations>,
+    output: Representations,
+}
+
 #[derive(Deserialize)]
 pub struct DestParams {
     from: Ipv4Addr,
     key: Ipv4Addr,
 }
 
-pub async fn dest(Query(params): Query<DestParams>) -> String {
-    let octets: [u8; 4] = params
-        .from
-        .octets()
-        .into_iter()
-        .zip(params.key.octets().into_iter())
-        .map(|(from, key)| from.overflowing_add(key).0)
-        .collect::<Vec<u8>>()
-        .try_into()
-        .unwrap();
-
-    Ipv4Addr::from(octets).to_string()
+pub async fn dest(
+    Query(params): Query<DestParams>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    let addr = dest_v4_addr(params.from, params.key);
+    output.format.respond(
+        &[("from", params.from.into()), ("key", params.key.into())],
+        addr.into(),
+    )
 }
 
 #[derive(Deserialize)]
@@ -33,18 +193,12 @@ pub struct KeyParams {
     to: Ipv4Addr,
 }
 
-pub async fn key(Query(params): Query<KeyParams>) -> String {
-    let octets: [u8; 4] = params
-        .from
-        .octets()
-        .into_iter()
-        .zip(params.to.octets().into_iter())
-        .map(|(from, to)| to.overflowing_sub(from).0)
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
-    // dbg!(&octets);
-    Ipv4Addr::from(octets).to_string()
+pub async fn key(Query(params): Query<KeyParams>, Query(output): Query<OutputParams>) -> Response {
+    let addr = key_v4_addr(params.from, params.to);
+    output.format.respond(
+        &[("from", params.from.into()), ("to", params.to.into())],
+        addr.into(),
+    )
 }
 
 #[derive(Deserialize)]
@@ -53,14 +207,15 @@ pub struct DestV6Params {
     key: Ipv6Addr,
 }
 
-pub async fn dest_v6(Query(params): Query<DestV6Params>) -> String {
-    let octets: [u8; 16] = zip(params.from.octets(), params.key.octets())
-        .map(|(from, key)| from.bitxor(key))
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
-
-    Ipv6Addr::from(octets).to_string()
+pub async fn dest_v6(
+    Query(params): Query<DestV6Params>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    let addr = dest_v6_addr(params.from, params.key);
+    output.format.respond(
+        &[("from", params.from.into()), ("key", params.key.into())],
+        addr.into(),
+    )
 }
 
 #[derive(Deserialize)]
@@ -69,12 +224,730 @@ pub struct KeyV6Params {
     to: Ipv6Addr,
 }
 
-pub async fn key_v6(Query(params): Query<KeyV6Params>) -> String {
-    let octets: [u8; 16] = zip(params.from.octets(), params.to.octets())
-        .map(|(from, to)| to.bitxor(from))
-        .collect::<Vec<_>>()
-        .try_into()
-        .unwrap();
+pub async fn key_v6(
+    Query(params): Query<KeyV6Params>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    let addr = key_v6_addr(params.from, params.to);
+    output.format.respond(
+        &[("from", params.from.into()), ("to", params.to.into())],
+        addr.into(),
+    )
+}
+
+#[derive(Clone, Copy)]
+enum Transform {
+    Dest,
+    Key,
+}
+
+fn family(addr: &IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+/// Collapse IPv4-mapped (`::ffff:a.b.c.d`) and IPv4-compatible (`::a.b.c.d`)
+/// addresses to plain IPv4. `::` and `::1` are left alone.
+fn canonicalize(addr: IpAddr) -> IpAddr {
+    let IpAddr::V6(v6) = addr else {
+        return addr;
+    };
+    if let Some(v4) = v6.to_ipv4_mapped() {
+        return IpAddr::V4(v4);
+    }
+    match v6.to_ipv4() {
+        Some(v4) if u128::from(v6) > 1 => IpAddr::V4(v4),
+        _ => addr,
+    }
+}
+
+/// Apply `transform` to a pair of addresses of either family, after
+/// canonicalising both of them.
+fn transform_any(transform: Transform, from: IpAddr, operand: IpAddr) -> Result<IpAddr, String> {
+    match (transform, canonicalize(from), canonicalize(operand)) {
+        (Transform::Dest, IpAddr::V4(from), IpAddr::V4(key)) => {
+            Ok(IpAddr::V4(dest_v4_addr(from, key)))
+        }
+        (Transform::Dest, IpAddr::V6(from), IpAddr::V6(key)) => {
+            Ok(IpAddr::V6(dest_v6_addr(from, key)))
+        }
+        (Transform::Key, IpAddr::V4(from), IpAddr::V4(to)) => Ok(IpAddr::V4(key_v4_addr(from, to))),
+        (Transform::Key, IpAddr::V6(from), IpAddr::V6(to)) => Ok(IpAddr::V6(key_v6_addr(from, to))),
+        (_, from, operand) => Err(format!(
+            "Cannot combine {} address {from} with {} address {operand}",
+            family(&from),
+            family(&operand)
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnyDestParams {
+    from: IpAddr,
+    key: IpAddr,
+}
+
+pub async fn dest_any(
+    Query(params): Query<AnyDestParams>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    match transform_any(Transform::Dest, params.from, params.key) {
+        Ok(addr) => output
+            .format
+            .respond(&[("from", params.from), ("key", params.key)], addr),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnyKeyParams {
+    from: IpAddr,
+    to: IpAddr,
+}
+
+pub async fn key_any(
+    Query(params): Query<AnyKeyParams>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    match transform_any(Transform::Key, params.from, params.to) {
+        Ok(addr) => output
+            .format
+            .respond(&[("from", params.from), ("to", params.to)], addr),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+/// Upper bound on a JSON array body. NDJSON bodies are streamed and not limited.
+const BATCH_JSON_LIMIT: usize = 64 * 1024 * 1024;
+/// Upper bound on a single NDJSON line.
+const NDJSON_LINE_LIMIT: usize = 64 * 1024;
+
+/// A single `/2/batch` operation. Addresses are handled as in `/2/any/*`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchItem {
+    Dest { from: IpAddr, key: IpAddr },
+    Key { from: IpAddr, to: IpAddr },
+}
+
+impl BatchItem {
+    fn apply(self) -> Result<IpAddr, String> {
+        match self {
+            Self::Dest { from, key } => transform_any(Transform::Dest, from, key),
+            Self::Key { from, to } => transform_any(Transform::Key, from, to),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchOutcome {
+    Ok { index: usize, result: IpAddr },
+    Err { index: usize, error: String },
+}
+
+impl BatchOutcome {
+    fn new(index: usize, item: Result<BatchItem, serde_json::Error>) -> Self {
+        match item
+            .map_err(|e| format!("Invalid item: {e}"))
+            .and_then(BatchItem::apply)
+        {
+            Ok(result) => Self::Ok { index, result },
+            Err(error) => Self::Err { index, error },
+        }
+    }
+
+    /// Serialize as a single NDJSON line.
+    fn into_line(self) -> String {
+        let mut line = serde_json::to_string(&self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+/// Incremental state of [`ndjson_lines`].
+struct LineReader {
+    chunks: BodyDataStream,
+    buffer: Vec<u8>,
+    /// How much of `buffer` is known not to contain a newline.
+    scanned: usize,
+    /// Whether the start of `buffer` belongs to a line already reported as
+    /// too long, and is to be discarded up to its newline.
+    overlong: bool,
+    done: bool,
+}
+
+impl LineReader {
+    /// The next line, or the reason it could not be read.
+    async fn next_line(&mut self) -> Option<Result<Vec<u8>, String>> {
+        loop {
+            if let Some(pos) = self.buffer[self.scanned..].iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=self.scanned + pos).collect();
+                self.scanned = 0;
+                if std::mem::take(&mut self.overlong) {
+                    continue;
+                }
+                return Some(Self::check_length(line));
+            }
+            self.scanned = self.buffer.len();
+            if self.buffer.len() > NDJSON_LINE_LIMIT {
+                self.buffer.clear();
+                self.scanned = 0;
+                if !std::mem::replace(&mut self.overlong, true) {
+                    return Some(Err(Self::too_long()));
+                }
+            }
+            if self.done {
+                let line = std::mem::take(&mut self.buffer);
+                self.scanned = 0;
+                if std::mem::take(&mut self.overlong) || line.is_empty() {
+                    return None;
+                }
+                return Some(Self::check_length(line));
+            }
+            match self.chunks.next().await {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    self.buffer.clear();
+                    self.scanned = 0;
+                    self.overlong = false;
+                    self.done = true;
+                    return Some(Err(format!("Failed to read body: {e}")));
+                }
+                None => self.done = true,
+            }
+        }
+    }
+
+    fn check_length(line: Vec<u8>) -> Result<Vec<u8>, String> {
+        match line.trim_ascii_end().len() > NDJSON_LINE_LIMIT {
+            true => Err(Self::too_long()),
+            false => Ok(line),
+        }
+    }
+
+    fn too_long() -> String {
+        format!("Line longer than {NDJSON_LINE_LIMIT} bytes")
+    }
+}
+
+/// Split a streamed body into its non-empty lines, without waiting for the
+/// whole body to arrive. Lines longer than [`NDJSON_LINE_LIMIT`] are reported
+/// as errors and skipped, so one endless line cannot exhaust memory.
+fn ndjson_lines(body: Body) -> impl Stream<Item = Result<Vec<u8>, String>> {
+    let reader = LineReader {
+        chunks: body.into_data_stream(),
+        buffer: Vec::new(),
+        scanned: 0,
+        overlong: false,
+        done: false,
+    };
+    stream::unfold(reader, |mut reader| async move {
+        let line = reader.next_line().await?;
+        Some((line, reader))
+    })
+    .filter(|line| {
+        let keep = match line {
+            Ok(line) => !line.trim_ascii().is_empty(),
+            Err(_) => true,
+        };
+        async move { keep }
+    })
+}
+
+/// Apply many `dest`/`key` operations in one request.
+///
+/// Accepts either a JSON array or, with `Content-Type: application/x-ndjson`,
+/// one item per line. Results are streamed back as NDJSON in input order,
+/// one line per item, carrying either a `result` or an `error`.
+pub async fn batch(headers: HeaderMap, body: Body) -> Response {
+    let is_ndjson = headers
+        .get(CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(|content_type| content_type.parse::<Mime>().ok())
+        .is_some_and(|mime| mime.essence_str() == "application/x-ndjson");
+
+    let lines = if is_ndjson {
+        ndjson_lines(body)
+            .enumerate()
+            .map(|(index, line)| match line {
+                Ok(line) => BatchOutcome::new(index, serde_json::from_slice(&line)).into_line(),
+                Err(error) => BatchOutcome::Err { index, error }.into_line(),
+            })
+            .boxed()
+    } else {
+        let Ok(bytes) = axum::body::to_bytes(body, BATCH_JSON_LIMIT).await else {
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Batch too large").into_response();
+        };
+        let Ok(items) = serde_json::from_slice::<Vec<serde_json::Value>>(&bytes) else {
+            return (StatusCode::BAD_REQUEST, "Expected a JSON array of items").into_response();
+        };
+        stream::iter(items.into_iter().enumerate())
+            .map(|(index, item)| BatchOutcome::new(index, serde_json::from_value(item)).into_line())
+            .boxed()
+    };
+
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines.map(Ok::<_, Infallible>)),
+    )
+        .into_response()
+}
+
+const ANONYMIZE_SECRET: &str = "IP_ANONYMIZE_SECRET";
+const DEANONYMIZE_TOKEN: &str = "IP_DEANONYMIZE_TOKEN";
+const FEISTEL_ROUNDS: u8 = 8;
+
+/// Keyed, format-preserving permutation of IP addresses.
+///
+/// By default addresses are run through a balanced Feistel network over the
+/// full address width. In prefix-preserving mode each bit is instead flipped
+/// according to the bits before it, so addresses sharing an `n`-bit prefix
+/// map to addresses sharing an `n`-bit prefix.
+struct AddrCipher {
+    mac: Hmac<Sha256>,
+}
+
+impl AddrCipher {
+    fn new(secret: &str) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length"),
+        }
+    }
+
+    fn prf(&self, domain: [u8; 3], data: u128) -> [u8; 32] {
+        let mut mac = self.mac.clone();
+        mac.update(&domain);
+        mac.update(&data.to_be_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    fn round(&self, bits: u32, round: u8, half: u128) -> u128 {
+        let digest = self.prf([b'F', bits as u8, round], half);
+        u128::from_be_bytes(digest[..16].try_into().unwrap())
+    }
+
+    fn feistel(&self, value: u128, bits: u32, decrypt: bool) -> u128 {
+        let half_bits = bits / 2;
+        let mask = (1u128 << half_bits) - 1;
+        let (mut left, mut right) = (value >> half_bits, value & mask);
+        for round in 0..FEISTEL_ROUNDS {
+            if decrypt {
+                let round = FEISTEL_ROUNDS - 1 - round;
+                (left, right) = (right ^ (self.round(bits, round, left) & mask), left);
+            } else {
+                (left, right) = (right, left ^ (self.round(bits, round, right) & mask));
+            }
+        }
+        (left << half_bits) | right
+    }
+
+    fn prefix_preserving(&self, value: u128, bits: u32, decrypt: bool) -> u128 {
+        let mut output = 0u128;
+        for i in 0..bits {
+            // The flip for bit `i` depends on the plaintext prefix before it,
+            // which is the input when encrypting and the output when decrypting.
+            let plaintext = if decrypt { output } else { value };
+            let prefix = plaintext.checked_shr(bits - i).unwrap_or(0);
+            let flip = (self.prf([b'P', bits as u8, i as u8], prefix)[0] >> 7) as u128;
+            let bit = (value >> (bits - 1 - i)) & 1;
+            output |= (bit ^ flip) << (bits - 1 - i);
+        }
+        output
+    }
+
+    fn permute(&self, value: u128, bits: u32, prefix: bool, decrypt: bool) -> u128 {
+        if prefix {
+            self.prefix_preserving(value, bits, decrypt)
+        } else {
+            self.feistel(value, bits, decrypt)
+        }
+    }
+
+    fn apply(&self, addr: IpAddr, prefix: bool, decrypt: bool) -> IpAddr {
+        match canonicalize(addr) {
+            IpAddr::V4(v4) => {
+                let value = self.permute(u32::from(v4).into(), 32, prefix, decrypt);
+                IpAddr::V4(Ipv4Addr::from(value as u32))
+            }
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(self.permute(
+                v6.into(),
+                128,
+                prefix,
+                decrypt,
+            ))),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnonymizeParams {
+    addr: IpAddr,
+    #[serde(default)]
+    prefix: bool,
+}
+
+pub async fn anonymize(
+    State(state): State<AppState>,
+    Query(params): Query<AnonymizeParams>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    let Some(secret) = state.read().await.secrets.get(ANONYMIZE_SECRET) else {
+        return (
+            StatusCode::FAILED_DEPENDENCY,
+            "Failed to load secrets".to_string(),
+        )
+            .into_response();
+    };
+    let cipher = AddrCipher::new(&secret);
+    let addr = cipher.apply(params.addr, params.prefix, false);
+    output.format.respond(&[("addr", params.addr)], addr)
+}
+
+/// Reverse `/2/anonymize`. Requires `Authorization: Bearer <token>`.
+pub async fn deanonymize(
+    State(state): State<AppState>,
+    header: HeaderMap,
+    Query(params): Query<AnonymizeParams>,
+    Query(output): Query<OutputParams>,
+) -> Response {
+    let secrets = &state.read().await.secrets;
+    let (Some(secret), Some(token)) = (
+        secrets.get(ANONYMIZE_SECRET),
+        secrets.get(DEANONYMIZE_TOKEN),
+    ) else {
+        return (
+            StatusCode::FAILED_DEPENDENCY,
+            "Failed to load secrets".to_string(),
+        )
+            .into_response();
+    };
+
+    if !bearer_matches(&header, &token) {
+        return (StatusCode::UNAUTHORIZED, "Invalid token".to_string()).into_response();
+    }
+
+    let cipher = AddrCipher::new(&secret);
+    let addr = cipher.apply(params.addr, params.prefix, true);
+    output.format.respond(&[("addr", params.addr)], addr)
+}
+
+/// A hardware address, either EUI-48 (MAC-48) or EUI-64.
+///
+/// Parses `aa:bb:cc:dd:ee:ff`, `aa-bb-cc-dd-ee-ff`, `aabb.ccdd.eeff` and bare
+/// `aabbccddeeff`, plus the same notations with eight octets.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub enum MacAddr {
+    Eui48([u8; 6]),
+    Eui64([u8; 8]),
+}
+
+impl MacAddr {
+    fn octets(&self) -> &[u8] {
+        match self {
+            Self::Eui48(octets) => octets,
+            Self::Eui64(octets) => octets,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Eui48(_) => "EUI-48",
+            Self::Eui64(_) => "EUI-64",
+        }
+    }
+
+    /// Modified EUI-64 interface identifier (RFC 4291 appendix A): the
+    /// universal/local bit is inverted and, for EUI-48, `ff:fe` is inserted in
+    /// the middle.
+    fn to_modified_eui64(self) -> Self {
+        match self {
+            Self::Eui48([a, b, c, d, e, f]) => Self::Eui64([a ^ 0x02, b, c, 0xff, 0xfe, d, e, f]),
+            Self::Eui64([a, b, c, d, e, f, g, h]) => Self::Eui64([a ^ 0x02, b, c, d, e, f, g, h]),
+        }
+    }
+
+    fn transform(transform: Transform, from: Self, operand: Self) -> Result<Self, String> {
+        match (transform, from, operand) {
+            (Transform::Dest, Self::Eui48(from), Self::Eui48(key)) => {
+                Ok(Self::Eui48(add_octets(from, key)))
+            }
+            (Transform::Dest, Self::Eui64(from), Self::Eui64(key)) => {
+                Ok(Self::Eui64(add_octets(from, key)))
+            }
+            (Transform::Key, Self::Eui48(from), Self::Eui48(to)) => {
+                Ok(Self::Eui48(sub_octets(from, to)))
+            }
+            (Transform::Key, Self::Eui64(from), Self::Eui64(to)) => {
+                Ok(Self::Eui64(sub_octets(from, to)))
+            }
+            (_, from, operand) => Err(format!(
+                "Cannot combine {} address {from} with {} address {operand}",
+                from.kind(),
+                operand.kind()
+            )),
+        }
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid MAC address: {s}");
+        // `from_str_radix` would also take a leading sign.
+        if !s
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || matches!(c, ':' | '-' | '.'))
+        {
+            return Err(invalid());
+        }
+        let octets = if let Some(separator) = s.chars().find(|c| matches!(c, ':' | '-')) {
+            s.split(separator)
+                .map(|group| match group.len() {
+                    1 | 2 => u8::from_str_radix(group, 16).map_err(|_| invalid()),
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let groups = s.split('.').collect::<Vec<_>>();
+            if groups.len() > 1 && groups.iter().any(|group| group.len() != 4) {
+                return Err(invalid());
+            }
+            let digits = groups.concat();
+            if digits.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            (0..digits.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        if let Ok(octets) = octets.as_slice().try_into() {
+            Ok(Self::Eui48(octets))
+        } else if let Ok(octets) = octets.as_slice().try_into() {
+            Ok(Self::Eui64(octets))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups = self
+            .octets()
+            .iter()
+            .map(|octet| format!("{octet:02x}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", groups.join(":"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MacDestParams {
+    from: MacAddr,
+    key: MacAddr,
+    #[serde(default)]
+    eui64: bool,
+}
+
+pub async fn dest_mac(Query(params): Query<MacDestParams>) -> impl IntoResponse {
+    match MacAddr::transform(Transform::Dest, params.from, params.key) {
+        Ok(addr) if params.eui64 => (StatusCode::OK, addr.to_modified_eui64().to_string()),
+        Ok(addr) => (StatusCode::OK, addr.to_string()),
+        Err(e) => (StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MacKeyParams {
+    from: MacAddr,
+    to: MacAddr,
+    #[serde(default)]
+    eui64: bool,
+}
+
+pub async fn key_mac(Query(params): Query<MacKeyParams>) -> impl IntoResponse {
+    match MacAddr::transform(Transform::Key, params.from, params.to) {
+        Ok(addr) if params.eui64 => (StatusCode::OK, addr.to_modified_eui64().to_string()),
+        Ok(addr) => (StatusCode::OK, addr.to_string()),
+        Err(e) => (StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRS: &[&str] = &[
+        "0.0.0.0",
+        "10.0.0.1",
+        "192.168.1.254",
+        "255.255.255.255",
+        "::",
+        "2001:db8::1",
+        "fe80::1ff:fe23:4567:890a",
+        "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+    ];
+
+    fn lines(chunks: &[&[u8]]) -> Vec<Result<Vec<u8>, String>> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok::<_, Infallible>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let body = Body::from_stream(stream::iter(chunks));
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(ndjson_lines(body).collect())
+    }
+
+    fn ok(line: &str) -> Result<Vec<u8>, String> {
+        Ok(line.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn blank_lines_are_skipped_and_lines_joined_across_chunks() {
+        assert_eq!(
+            lines(&[b"a", b"b\nc", b"d\n", b"\n", b" \r\ne\n"]),
+            [ok("ab\n"), ok("cd\n"), ok("e\n")]
+        );
+        assert_eq!(lines(&[b"x\r", b"\ny\r\n"]), [ok("x\r\n"), ok("y\r\n")]);
+    }
+
+    #[test]
+    fn the_last_line_needs_no_newline() {
+        assert_eq!(lines(&[b"a\nla", b"st"]), [ok("a\n"), ok("last")]);
+        assert_eq!(lines(&[b"a\n"]), [ok("a\n")]);
+        assert_eq!(lines(&[]), []);
+    }
+
+    #[test]
+    fn overlong_lines_are_reported_once_and_skipped() {
+        let too_long = Err(LineReader::too_long());
+        let half = vec![b'x'; NDJSON_LINE_LIMIT / 2 + 1];
+
+        // Spread over chunks, discarded up to its newline.
+        assert_eq!(
+            lines(&[&half, &half, &half, b"tail\nok\n"]),
+            [too_long.clone(), ok("ok\n")]
+        );
+        // Within one chunk.
+        let mut chunk = vec![b'x'; NDJSON_LINE_LIMIT + 1];
+        chunk.extend_from_slice(b"\nok\n");
+        assert_eq!(lines(&[&chunk]), [too_long.clone(), ok("ok\n")]);
+        // At the end of the body.
+        assert_eq!(lines(&[b"ok\n", &half, &half]), [ok("ok\n"), too_long]);
+
+        // The line ending doesn't count towards the limit.
+        let mut exact = vec![b'x'; NDJSON_LINE_LIMIT];
+        exact.extend_from_slice(b"\r\n");
+        assert_eq!(lines(&[&exact]), [Ok(exact.clone())]);
+    }
+
+    #[test]
+    fn mac_addresses_parse_in_every_notation() {
+        let octets = [0xaa, 0xbb, 0xcc, 0x0d, 0xee, 0xff];
+        for notation in [
+            "aa:bb:cc:0d:ee:ff",
+            "AA-BB-CC-0D-EE-FF",
+            "aa:bb:cc:d:ee:ff",
+            "aabb.cc0d.eeff",
+            "aabbcc0deeff",
+        ] {
+            match notation.parse::<MacAddr>() {
+                Ok(MacAddr::Eui48(parsed)) => assert_eq!(parsed, octets, "{notation}"),
+                _ => panic!("{notation} should parse as EUI-48"),
+            }
+        }
+        assert!(matches!(
+            "00:11:22:33:44:55:66:77".parse(),
+            Ok(MacAddr::Eui64(_))
+        ));
+        assert!(matches!(
+            "0011.2233.4455.6677".parse(),
+            Ok(MacAddr::Eui64(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_mac_addresses_are_rejected() {
+        for notation in [
+            "",
+            "aa:bb:cc:dd:ee",
+            "aa:bb:cc:dd:ee:ff:00",
+            "aa:bb:cc:dd:ee:+f",
+            "aa:bb:cc:dd:ee:-f",
+            "aa:bb:cc:dd:ee:fff",
+            "aa:bb-cc:dd:ee:ff",
+            "aabb.ccdd.eef",
+            "+abbccddeeff",
+            "aabbccddeefg",
+            "ääbbccddeeff",
+        ] {
+            assert!(notation.parse::<MacAddr>().is_err(), "{notation}");
+        }
+    }
+
+    #[test]
+    fn modified_eui64_flips_the_universal_local_bit() {
+        let addr: MacAddr = "00:11:22:33:44:55".parse().unwrap();
+        assert_eq!(
+            addr.to_modified_eui64().to_string(),
+            "02:11:22:ff:fe:33:44:55"
+        );
+    }
+
+    #[test]
+    fn anonymization_round_trips() {
+        let cipher = AddrCipher::new("secret");
+        for addr in ADDRS {
+            let addr: IpAddr = addr.parse().unwrap();
+            for prefix in [false, true] {
+                let anonymized = cipher.apply(addr, prefix, false);
+                assert_eq!(family(&anonymized), family(&addr));
+                assert_eq!(cipher.apply(anonymized, prefix, true), addr, "{addr}");
+            }
+        }
+    }
+
+    #[test]
+    fn anonymization_depends_on_secret() {
+        let addr = "10.0.0.1".parse().unwrap();
+        let anonymized = AddrCipher::new("secret").apply(addr, false, false);
+        assert_ne!(anonymized, addr);
+        assert_ne!(
+            AddrCipher::new("other").apply(addr, false, false),
+            anonymized
+        );
+    }
 
-    Ipv6Addr::from(octets).to_string()
+    #[test]
+    fn prefix_preserving_mode_keeps_shared_prefixes() {
+        let cipher = AddrCipher::new("secret");
+        let anonymize = |addr: &str| match cipher.apply(addr.parse().unwrap(), true, false) {
+            IpAddr::V4(v4) => u32::from(v4),
+            IpAddr::V6(_) => unreachable!(),
+        };
+        // Share exactly 24 bits.
+        let (a, b) = (anonymize("192.168.1.10"), anonymize("192.168.1.200"));
+        assert_eq!(a >> 8, b >> 8);
+        // Share exactly 16 bits.
+        let c = anonymize("192.168.129.10");
+        assert_eq!(a >> 16, c >> 16);
+        assert_ne!(a >> 15, c >> 15);
+    }
 }
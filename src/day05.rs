@@ -1,145 +1,909 @@
-use std::str::FromStr;
+mod analyze;
+mod batch;
+mod compat;
+mod convert;
+mod features;
+mod lint;
+mod policy;
+mod store;
+mod workspace;
+
+pub use analyze::analyze_manifest;
+pub use batch::{batch, BATCH_BODY_LIMIT};
+pub use compat::compat;
+pub use convert::convert;
+pub use lint::lint;
+pub use policy::{load_policies, POLICIES_PATH};
+pub use store::{order_totals, orders};
+
+use std::fmt;
 
 use axum::{
-    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
-    response::IntoResponse,
+    extract::{FromRequest, Multipart, Query, Request, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
 };
 use cargo_manifest::{Manifest, MaybeInherited};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use toml::Value;
+use crate::AppState;
+use policy::Policy;
 
-fn _validate_manifest<T>(manifest: Manifest<T, T>) -> Result<T, (StatusCode, &'static str)> {
-    let Some(package) = manifest.package else {
-        return Err((StatusCode::NO_CONTENT, "Empty package"));
-    };
-    if let Some(MaybeInherited::Local(keywords)) = package.keywords {
-        if !keywords.contains(&"Christmas 2024".to_string()) {
-            return Err((StatusCode::BAD_REQUEST, "Magic keyword not provided"));
+/// Response header carrying the ID of a package stored with `store=true`.
+const PACKAGE_ID_HEADER: &str = "x-package-id";
+
+/// Manifest in the common model every format is parsed into. Metadata tables
+/// are kept as JSON values regardless of the original format.
+type AnyManifest = Manifest<Value, Value>;
+
+/// A supported manifest serialization format.
+///
+/// Adding a format is a matter of adding an entry to [`FORMATS`] with a parser
+/// producing the generic document tree, a serializer for the reverse, and
+/// hooks for what that tree cannot carry. Besides its media type and aliases, a format is also recognised by a
+/// structured syntax suffix equal to its name (e.g. `application/vnd.foo+json`).
+struct ManifestFormat {
+    name: &'static str,
+    media_type: &'static str,
+    aliases: &'static [&'static str],
+    /// File extensions of multipart parts in this format.
+    extensions: &'static [&'static str],
+    parse: fn(&str) -> Result<Value, String>,
+    serialize: fn(&Value) -> Result<String, String>,
+    /// Whether a document without `package` or `workspace` is a legacy
+    /// manifest whose package lives at the root (or under `project`).
+    legacy_root: bool,
+    /// Marker starting a line comment, for formats that have them.
+    comment: Option<&'static str>,
+    /// Whether TOML datetimes are kept as such rather than as strings.
+    datetimes: bool,
+    /// Why a value cannot be written in this format, if it cannot.
+    unrepresentable: fn(&Value) -> Option<&'static str>,
+    /// Paths of the NaN and infinite floats in a body, which the generic
+    /// document turns into `null`.
+    non_finite_floats: fn(&str) -> Vec<String>,
+    /// Rewrites a body into this same format without going through the
+    /// generic document, to keep what it would lose.
+    normalize: Option<Rewrite>,
+}
+
+type Rewrite = fn(&str) -> Result<String, String>;
+
+/// Sniffing tries formats in this order, so stricter formats come first.
+const FORMATS: &[ManifestFormat] = &[
+    ManifestFormat {
+        name: "json",
+        media_type: "application/json",
+        aliases: &["text/json", "application/x-json"],
+        extensions: &["json"],
+        parse: |body| serde_json::from_str(body).map_err(|e| e.to_string()),
+        serialize: |document| serde_json::to_string_pretty(document).map_err(|e| e.to_string()),
+        legacy_root: false,
+        comment: None,
+        datetimes: false,
+        unrepresentable: |_| None,
+        non_finite_floats: |_| vec![],
+        normalize: None,
+    },
+    ManifestFormat {
+        name: "toml",
+        media_type: "application/toml",
+        aliases: &["application/x-toml", "text/toml", "text/x-toml"],
+        extensions: &["toml"],
+        parse: |body| toml::from_str(body).map_err(|e| e.to_string()),
+        serialize: |document| toml::to_string_pretty(document).map_err(|e| e.to_string()),
+        legacy_root: true,
+        comment: Some("#"),
+        datetimes: true,
+        unrepresentable: convert::toml_unrepresentable,
+        non_finite_floats: |body| convert::non_finite_floats(toml::from_str(body).ok()),
+        normalize: Some(convert::normalize_toml),
+    },
+    ManifestFormat {
+        name: "yaml",
+        media_type: "application/yaml",
+        aliases: &["application/x-yaml", "text/yaml", "text/x-yaml"],
+        extensions: &["yaml", "yml"],
+        parse: |body| serde_yaml::from_str(body).map_err(|e| e.to_string()),
+        serialize: |document| serde_yaml::to_string(document).map_err(|e| e.to_string()),
+        legacy_root: false,
+        comment: Some("#"),
+        datetimes: false,
+        unrepresentable: |_| None,
+        non_finite_floats: |body| convert::non_finite_floats(serde_yaml::from_str(body).ok()),
+        normalize: None,
+    },
+];
+
+impl ManifestFormat {
+    fn from_name(name: &str) -> Option<&'static Self> {
+        FORMATS.iter().find(|format| format.name == name)
+    }
+
+    fn from_mime(mime: &Mime) -> Option<&'static Self> {
+        // Bodies are read as UTF-8.
+        if mime
+            .get_param(mime::CHARSET)
+            .is_some_and(|charset| charset != mime::UTF_8)
+        {
+            return None;
         }
-    } else {
-        return Err((StatusCode::BAD_REQUEST, "Magic keyword not provided"));
+        let essence = mime.essence_str();
+        FORMATS
+            .iter()
+            .find(|format| format.media_type == essence || format.aliases.contains(&essence))
+            .or_else(|| {
+                let suffix = mime.suffix()?;
+                FORMATS.iter().find(|format| format.name == suffix.as_str())
+            })
+    }
+
+    fn from_media_type(content_type: &str) -> Option<&'static Self> {
+        Self::from_mime(&content_type.parse().ok()?)
+    }
+
+    /// Guess the format of a body sent without a usable content type: the
+    /// first format that parses it into a table wins.
+    fn sniff(body: &str) -> Option<&'static Self> {
+        FORMATS
+            .iter()
+            .find(|format| (format.parse)(body).is_ok_and(|document| document.is_object()))
+    }
+
+    /// Format of a multipart part, from its content type or else its file
+    /// extension. Parts with neither are assumed to be TOML.
+    fn from_part(content_type: Option<&str>, file_name: Option<&str>) -> Option<&'static Self> {
+        if let Some(content_type) = content_type {
+            if let Some(format) = Self::from_media_type(content_type) {
+                return Some(format);
+            }
+        }
+        let extension = file_name.and_then(|name| name.rsplit_once('.'));
+        FORMATS
+            .iter()
+            .find(|format| {
+                extension.is_some_and(|(_, extension)| format.extensions.contains(&extension))
+            })
+            .or_else(|| Self::from_name("toml"))
+    }
+
+    fn parse_manifest(&self, body: &str) -> Option<AnyManifest> {
+        self.manifest_from_document((self.parse)(body).ok()?)
+    }
+
+    fn manifest_from_document(&self, document: Value) -> Option<AnyManifest> {
+        let mut manifest: AnyManifest = serde_json::from_value(document.clone()).ok()?;
+        // Like Cargo, treat a TOML document without `[package]` or
+        // `[workspace]` as a legacy manifest whose package lives at the root
+        // (or `[project]`). Other formats never had such manifests.
+        if self.legacy_root && manifest.package.is_none() && manifest.workspace.is_none() {
+            let root = document.get("project").cloned().unwrap_or(document);
+            manifest.package = Some(serde_json::from_value(root).ok()?);
+        }
+        Some(manifest)
     }
-    let Some(metadata) = package.metadata else {
-        return Err((StatusCode::NO_CONTENT, "Empty metadata"));
-    };
-    Ok(metadata)
 }
 
-fn process_toml(body: String) -> (StatusCode, String) {
-    let Ok(manifest) = Manifest::from_str(&body) else {
-        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-    };
+/// A single validated entry of `package.metadata.orders`.
+#[derive(Clone, Debug, Serialize)]
+struct Order {
+    item: String,
+    quantity: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    /// Price per unit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<f64>,
+}
 
-    let metadata = match _validate_manifest(manifest) {
-        Ok(metadata) => metadata,
-        Err((status_code, static_str)) => return (status_code, static_str.to_string()),
-    };
+impl Order {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        let Value::Object(order) = value else {
+            return Err("Order must be a table".to_string());
+        };
+        let item = match order.get("item") {
+            Some(Value::String(item)) if !item.trim().is_empty() => item.clone(),
+            Some(Value::String(_)) => return Err("Item must not be empty".to_string()),
+            Some(_) => return Err("Item must be a string".to_string()),
+            None => return Err("Missing item".to_string()),
+        };
+        let quantity = match order.get("quantity") {
+            Some(Value::Number(quantity)) => match quantity.as_u64() {
+                Some(quantity) if quantity > 0 => quantity,
+                _ => {
+                    return Err(format!(
+                        "Quantity must be a positive integer, got {quantity}"
+                    ))
+                }
+            },
+            Some(_) => return Err("Quantity must be a number".to_string()),
+            None => return Err("Missing quantity".to_string()),
+        };
+        let unit = match order.get("unit") {
+            Some(Value::String(unit)) => Some(unit.clone()),
+            Some(_) => return Err("Unit must be a string".to_string()),
+            None => None,
+        };
+        let price = match order.get("price") {
+            Some(Value::Number(price)) => match price.as_f64() {
+                Some(price) if price >= 0.0 => Some(price),
+                _ => return Err(format!("Price must be a non-negative number, got {price}")),
+            },
+            Some(_) => return Err("Price must be a number".to_string()),
+            None => None,
+        };
+        Ok(Self {
+            item,
+            quantity,
+            unit,
+            price,
+        })
+    }
 
-    let maybe_orders = match metadata.get("orders") {
-        Some(Value::Array(maybe_orders)) => maybe_orders,
-        Some(_) => return (StatusCode::BAD_REQUEST, "Invalid metadata".to_string()),
-        None => return (StatusCode::NO_CONTENT, "No orders".to_string()),
-    };
+    /// Merge orders for the same item and unit, keeping first-seen order.
+    /// The merged price is kept only if every merged order agrees on it.
+    fn aggregate(orders: Vec<Self>) -> Result<Vec<Self>, ManifestError> {
+        let mut aggregated: Vec<Self> = vec![];
+        for order in orders {
+            match aggregated
+                .iter_mut()
+                .find(|other| other.item == order.item && other.unit == order.unit)
+            {
+                Some(other) => {
+                    other.quantity = other
+                        .quantity
+                        .checked_add(order.quantity)
+                        .ok_or(QUANTITY_OVERFLOW)?;
+                    if other.price != order.price {
+                        other.price = None;
+                    }
+                }
+                None => aggregated.push(order),
+            }
+        }
+        Ok(aggregated)
+    }
+}
 
-    let summary = maybe_orders
-        .iter()
-        .filter_map(|value| match (value.get("item"), value.get("quantity")) {
-            (Some(Value::String(item)), Some(Value::Integer(quantity))) => {
-                Some(format!("{item}: {quantity}"))
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.item, self.quantity)?;
+        if let Some(unit) = &self.unit {
+            write!(f, " {unit}")?;
+        }
+        if let Some(price) = self.price {
+            write!(f, " @ {price}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct OrderTotals {
+    orders: usize,
+    quantity: u64,
+    /// Sum of `quantity * price` over the orders that have a price.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct PackageInfo {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OrderSummary {
+    package: PackageInfo,
+    orders: Vec<Order>,
+    /// Only computed when aggregating, so that quantities which cannot be
+    /// summed are still listed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<OrderTotals>,
+}
+
+impl OrderSummary {
+    fn new(
+        package: PackageInfo,
+        orders: Vec<Order>,
+        aggregate: bool,
+    ) -> Result<Self, ManifestError> {
+        if !aggregate {
+            return Ok(Self {
+                package,
+                orders,
+                totals: None,
+            });
+        }
+        let priced = orders
+            .iter()
+            .filter_map(|order| Some(order.price? * order.quantity as f64))
+            .collect::<Vec<_>>();
+        let totals = Some(OrderTotals {
+            orders: orders.len(),
+            quantity: orders
+                .iter()
+                .try_fold(0u64, |total, order| total.checked_add(order.quantity))
+                .ok_or(QUANTITY_OVERFLOW)?,
+            price: (!priced.is_empty()).then(|| priced.iter().sum()),
+        });
+        let orders = Order::aggregate(orders)?;
+        Ok(Self {
+            package,
+            orders,
+            totals,
+        })
+    }
+}
+
+/// Representation of an [`OrderSummary`] chosen from the `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SummaryFormat {
+    Text,
+    Json,
+    Toml,
+    Yaml,
+    Csv,
+}
+
+/// Structured form of a summary. Totals are only included when aggregating.
+#[derive(Serialize)]
+struct SummaryDocument<'a> {
+    orders: &'a [Order],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<&'a OrderTotals>,
+}
+
+impl SummaryFormat {
+    /// Every format, in the order wildcard ranges prefer them.
+    const ALL: [Self; 5] = [Self::Text, Self::Csv, Self::Json, Self::Toml, Self::Yaml];
+
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Text => &["text/plain"],
+            Self::Json => &["application/json"],
+            Self::Toml => &["application/toml"],
+            Self::Yaml => &["application/yaml", "application/x-yaml", "text/yaml"],
+            Self::Csv => &["text/csv"],
+        }
+    }
+
+    /// How specifically `media_range` names this format: 2 for one of its
+    /// media types, 1 for `type/*` and 0 for `*/*`.
+    fn specificity(self, media_range: &str) -> Option<u8> {
+        if media_range == "*/*" {
+            return Some(0);
+        }
+        self.media_types().iter().find_map(|media_type| {
+            if *media_type == media_range {
+                Some(2)
+            } else {
+                let (kind, _) = media_type.split_once('/')?;
+                (media_range.strip_suffix("/*") == Some(kind)).then_some(1)
             }
-            _ => None,
         })
-        .collect::<Vec<_>>();
+    }
+
+    /// Pick the format with the highest quality, preferring formats matched
+    /// by earlier ranges on ties. A missing or empty `Accept` header means
+    /// plain text. As in RFC 9110, a format takes the quality of the most
+    /// specific range matching it, so `text/plain;q=0, */*` refuses plain
+    /// text but accepts every other format.
+    fn negotiate(header: &HeaderMap) -> Option<Self> {
+        let Some(accept) = header.get(ACCEPT) else {
+            return Some(Self::Text);
+        };
+        let media_ranges = accept
+            .to_str()
+            .ok()?
+            .split(',')
+            .filter_map(|media_range| {
+                let mut parts = media_range.split(';');
+                let media_range = parts.next()?.trim().to_ascii_lowercase();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!media_range.is_empty()).then_some((media_range, quality))
+            })
+            .collect::<Vec<_>>();
+        if media_ranges.is_empty() {
+            return Some(Self::Text);
+        }
+        let mut candidates = Self::ALL
+            .into_iter()
+            .filter_map(|format| {
+                // The first of the most specific ranges decides.
+                let (position, (_, quality)) = media_ranges
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(position, (media_range, quality))| {
+                        let specificity = format.specificity(media_range)?;
+                        Some((position, (specificity, *quality)))
+                    })
+                    .min_by_key(|&(position, (specificity, _))| {
+                        (u8::MAX - specificity, position)
+                    })?;
+                (quality > 0.0).then_some((format, position, quality))
+            })
+            .collect::<Vec<_>>();
+        // Stable, so formats keep the order of `ALL` on full ties.
+        candidates.sort_by(|(_, a_position, a), (_, b_position, b)| {
+            b.total_cmp(a).then(a_position.cmp(b_position))
+        });
+        candidates.first().map(|&(format, _, _)| format)
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => "text/plain; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Toml => "application/toml",
+            Self::Yaml => "application/yaml",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
 
-    if summary.is_empty() {
-        (StatusCode::NO_CONTENT, "No valid orders".to_string())
+    fn render(self, summary: &OrderSummary, aggregate: bool) -> Response {
+        let document = SummaryDocument {
+            orders: &summary.orders,
+            totals: summary.totals.as_ref(),
+        };
+        let body = match self {
+            Self::Text => {
+                let mut lines = summary
+                    .orders
+                    .iter()
+                    .map(Order::to_string)
+                    .collect::<Vec<_>>();
+                if let Some(totals) = &summary.totals {
+                    lines.push(format!("Total: {}", totals.quantity));
+                    if let Some(price) = totals.price {
+                        lines.push(format!("Total price: {price}"));
+                    }
+                }
+                Ok(lines.join("\n"))
+            }
+            Self::Json if aggregate => serde_json::to_string(&document).map_err(|e| e.to_string()),
+            Self::Json => serde_json::to_string(&summary.orders).map_err(|e| e.to_string()),
+            Self::Toml => toml::to_string(&document).map_err(|e| e.to_string()),
+            Self::Yaml if aggregate => serde_yaml::to_string(&document).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::to_string(&summary.orders).map_err(|e| e.to_string()),
+            Self::Csv => {
+                let mut csv = String::from("item,quantity,unit,price\n");
+                for order in &summary.orders {
+                    let fields = [
+                        csv_field(&order.item),
+                        order.quantity.to_string(),
+                        csv_field(order.unit.as_deref().unwrap_or_default()),
+                        order
+                            .price
+                            .map(|price| price.to_string())
+                            .unwrap_or_default(),
+                    ];
+                    csv.push_str(&fields.join(","));
+                    csv.push('\n');
+                }
+                Ok(csv)
+            }
+        };
+        match body {
+            Ok(body) => {
+                (StatusCode::OK, [(CONTENT_TYPE, self.content_type())], body).into_response()
+            }
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to serialize summary: {e}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
     } else {
-        (StatusCode::OK, summary.join("\n").to_string())
+        field.to_string()
     }
 }
 
-fn process_json(body: String) -> (StatusCode, String) {
-    let Ok(manifest) =
-        serde_json::from_str::<Manifest<serde_json::Value, serde_json::Value>>(&body)
-    else {
-        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
-    };
-    let metadata = match _validate_manifest(manifest) {
-        Ok(metadata) => metadata,
-        Err((status_code, static_str)) => return (status_code, static_str.to_string()),
-    };
+#[derive(Serialize)]
+struct OrderError {
+    index: usize,
+    error: String,
+}
+
+/// Quantities are added up as `u64`, and may not exceed it.
+const QUANTITY_OVERFLOW: ManifestError =
+    ManifestError::Rejected(StatusCode::BAD_REQUEST, "Total quantity too large");
 
+enum ManifestError {
+    Rejected(StatusCode, &'static str),
+    /// Only raised in strict mode.
+    InvalidOrders(Vec<OrderError>),
+}
+
+impl IntoResponse for ManifestError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Rejected(status_code, static_str) => {
+                (status_code, static_str.to_string()).into_response()
+            }
+            Self::InvalidOrders(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                [(CONTENT_TYPE, "application/json")],
+                serde_json::json!({ "errors": errors }).to_string(),
+            )
+                .into_response(),
+        }
+    }
+}
+
+fn extract_orders(metadata: &Value, strict: bool) -> Result<Vec<Order>, ManifestError> {
     let maybe_orders = match metadata.get("orders") {
-        Some(serde_json::Value::Array(maybe_orders)) => maybe_orders,
-        Some(_) => return (StatusCode::BAD_REQUEST, "Invalid metadata".to_string()),
-        None => return (StatusCode::NO_CONTENT, "No orders".to_string()),
+        Some(Value::Array(maybe_orders)) => maybe_orders,
+        Some(_) => {
+            return Err(ManifestError::Rejected(
+                StatusCode::BAD_REQUEST,
+                "Invalid metadata",
+            ))
+        }
+        None => return Err(ManifestError::Rejected(StatusCode::NO_CONTENT, "No orders")),
     };
-    let summary = maybe_orders
-        .iter()
-        .filter_map(|value| match (value.get("item"), value.get("quantity")) {
-            // TODO: Handle case when number is not an int
-            (Some(serde_json::Value::String(item)), Some(serde_json::Value::Number(quantity))) => {
-                Some(format!("{item}: {quantity}"))
-            }
-            _ => None,
-        })
-        .collect::<Vec<_>>();
 
-    if summary.is_empty() {
-        (StatusCode::NO_CONTENT, "No valid orders".to_string())
+    let mut orders = vec![];
+    let mut errors = vec![];
+    for (index, value) in maybe_orders.iter().enumerate() {
+        match Order::from_value(value) {
+            Ok(order) => orders.push(order),
+            Err(error) => errors.push(OrderError { index, error }),
+        }
+    }
+
+    if strict && !errors.is_empty() {
+        Err(ManifestError::InvalidOrders(errors))
+    } else if orders.is_empty() {
+        Err(ManifestError::Rejected(
+            StatusCode::NO_CONTENT,
+            "No valid orders",
+        ))
     } else {
-        (StatusCode::OK, summary.join("\n").to_string())
+        Ok(orders)
     }
 }
 
-fn process_yaml(body: String) -> (StatusCode, String) {
-    let Ok(manifest) =
-        serde_yaml::from_str::<Manifest<serde_yaml::Value, serde_yaml::Value>>(&body)
-    else {
-        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string());
+fn process(
+    format: &ManifestFormat,
+    body: &str,
+    params: &ManifestParams,
+) -> Result<OrderSummary, ManifestError> {
+    let manifest = format.parse_manifest(body).ok_or(ManifestError::Rejected(
+        StatusCode::BAD_REQUEST,
+        "Invalid manifest",
+    ))?;
+    summarize(manifest, params)
+}
+
+fn summarize(
+    manifest: AnyManifest,
+    params: &ManifestParams,
+) -> Result<OrderSummary, ManifestError> {
+    let package = manifest.package.as_ref().map(|package| PackageInfo {
+        name: package.name.clone(),
+        version: package.version.clone().and_then(MaybeInherited::as_local),
+    });
+    let metadata = Policy::from_name(params.policy.as_deref())
+        .and_then(|policy| policy.validate(manifest))
+        .map_err(|(status_code, static_str)| ManifestError::Rejected(status_code, static_str))?;
+    let orders = extract_orders(&metadata, params.strict)?;
+    // Validation has already rejected manifests without a package.
+    OrderSummary::new(package.unwrap(), orders, params.aggregate)
+}
+
+#[derive(Default, Deserialize)]
+pub struct ManifestParams {
+    /// Reject the whole manifest with a per-order report if any order is invalid,
+    /// instead of skipping invalid orders.
+    #[serde(default)]
+    strict: bool,
+    /// Merge duplicate items and append the totals.
+    #[serde(default)]
+    aggregate: bool,
+    /// Record the package and its orders in the database.
+    #[serde(default)]
+    store: bool,
+    /// Detect the format from the body when `Content-Type` is missing or
+    /// `application/octet-stream`.
+    #[serde(default)]
+    sniff: bool,
+    /// Name of the acceptance policy from `policies.toml`; `default` if unset.
+    policy: Option<String>,
+}
+
+/// Summarise the orders of a single manifest, or of every member of a
+/// workspace when uploaded as `multipart/form-data` (see [`workspace`]).
+pub async fn manifest(
+    State(state): State<AppState>,
+    Query(params): Query<ManifestParams>,
+    request: Request,
+) -> Response {
+    if let Err(rejection) = Policy::from_name(params.policy.as_deref()) {
+        return rejection.into_response();
+    }
+    let pool = match params.store {
+        true => Some(state.read().await.pool.clone()),
+        false => None,
     };
-    let metadata = match _validate_manifest(manifest) {
-        Ok(metadata) => metadata,
-        Err((status_code, static_str)) => return (status_code, static_str.to_string()),
+    let header = request.headers().clone();
+    // `None` if the header is absent, `Some(None)` if it cannot be parsed.
+    let media_type = header
+        .get(CONTENT_TYPE)
+        .map(|header_value| header_value.to_str().ok()?.parse::<Mime>().ok());
+    if let Some(Some(mime)) = &media_type {
+        if mime.type_() == mime::MULTIPART && mime.subtype() == mime::FORM_DATA {
+            return match Multipart::from_request(request, &()).await {
+                Ok(multipart) => {
+                    workspace::process_workspace(multipart, &params, pool.as_ref()).await
+                }
+                Err(e) => e.into_response(),
+            };
+        }
+    }
+    let sniffable = match &media_type {
+        None => true,
+        Some(Some(mime)) => mime.essence_str() == mime::APPLICATION_OCTET_STREAM.essence_str(),
+        Some(None) => false,
     };
+    let format = media_type
+        .flatten()
+        .and_then(|mime| ManifestFormat::from_mime(&mime));
+    if format.is_none() && !(params.sniff && sniffable) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Invalid content type header".to_string(),
+        )
+            .into_response();
+    }
 
-    let maybe_orders = match metadata.get("orders") {
-        Some(serde_yaml::Value::Sequence(maybe_orders)) => maybe_orders,
-        Some(_) => return (StatusCode::BAD_REQUEST, "Invalid metadata".to_string()),
-        None => return (StatusCode::NO_CONTENT, "No orders".to_string()),
+    let Some(output) = SummaryFormat::negotiate(&header) else {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            "Supported formats: text/plain, application/json, application/toml, application/yaml, text/csv"
+                .to_string(),
+        )
+            .into_response();
+    };
+
+    let Ok(body) = String::from_request(request, &()).await else {
+        return (StatusCode::BAD_REQUEST, "Invalid body".to_string()).into_response();
     };
-    let summary = maybe_orders
-        .iter()
-        .filter_map(|value| match (value.get("item"), value.get("quantity")) {
-            // TODO: Handle case when number is not an int
-            (Some(serde_yaml::Value::String(item)), Some(serde_yaml::Value::Number(quantity))) => {
-                Some(format!("{item}: {quantity}"))
+
+    let Some(format) = format.or_else(|| ManifestFormat::sniff(&body)) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unable to detect manifest format".to_string(),
+        )
+            .into_response();
+    };
+
+    let summary = match process(format, &body, &params) {
+        Ok(summary) => summary,
+        Err(e) => return e.into_response(),
+    };
+    let mut response = output.render(&summary, params.aggregate);
+    if let Some(pool) = pool {
+        match store::store_summary(&pool, &summary).await {
+            Ok(package_id) => {
+                response.headers_mut().insert(
+                    PACKAGE_ID_HEADER,
+                    HeaderValue::from_str(&package_id.to_string()).unwrap(),
+                );
             }
+            Err(_) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to store orders".to_string(),
+                )
+                    .into_response()
+            }
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summarize_as(format: &str, body: &str) -> Result<OrderSummary, ManifestError> {
+        summarize_with(format, body, &ManifestParams::default())
+    }
+
+    fn summarize_with(
+        format: &str,
+        body: &str,
+        params: &ManifestParams,
+    ) -> Result<OrderSummary, ManifestError> {
+        process(ManifestFormat::from_name(format).unwrap(), body, params)
+    }
+
+    fn rejection(
+        result: Result<OrderSummary, ManifestError>,
+    ) -> Option<(StatusCode, &'static str)> {
+        match result {
+            Err(ManifestError::Rejected(status_code, message)) => Some((status_code, message)),
             _ => None,
-        })
-        .collect::<Vec<_>>();
+        }
+    }
 
-    if summary.is_empty() {
-        (StatusCode::NO_CONTENT, "No valid orders".to_string())
-    } else {
-        (StatusCode::OK, summary.join("\n").to_string())
-    }
-}
-
-pub async fn manifest(header: HeaderMap, body: String) -> impl IntoResponse {
-    match header.get(CONTENT_TYPE) {
-        Some(header_value) => match header_value.to_str() {
-            Ok("application/toml") => process_toml(body),
-            Ok("application/json") => process_json(body),
-            Ok("application/yaml") => process_yaml(body),
-            _ => (
-                StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                "Invalid content type header".to_string(),
-            ),
-        },
-        None => (
-            StatusCode::UNSUPPORTED_MEDIA_TYPE,
-            "Invalid content type header".to_string(),
-        ),
+    fn negotiate(accept: &str) -> Option<SummaryFormat> {
+        let mut header = HeaderMap::new();
+        header.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        SummaryFormat::negotiate(&header)
+    }
+
+    #[test]
+    fn summary_format_negotiation_honours_zero_quality() {
+        assert_eq!(negotiate(""), Some(SummaryFormat::Text));
+        assert_eq!(
+            negotiate("text/csv;q=0.5, application/json"),
+            Some(SummaryFormat::Json)
+        );
+        assert_eq!(negotiate("text/plain;q=0"), None);
+        assert_eq!(negotiate("text/plain;q=0, */*"), Some(SummaryFormat::Csv));
+        assert_eq!(
+            negotiate("text/plain;q=0, text/csv;q=0.1"),
+            Some(SummaryFormat::Csv)
+        );
+        assert_eq!(negotiate("image/png"), None);
+    }
+
+    #[test]
+    fn media_types_name_their_format() {
+        let cases = [
+            ("application/json", Some("json")),
+            ("application/json; charset=utf-8", Some("json")),
+            ("application/json; charset=\"UTF-8\"", Some("json")),
+            ("application/json; charset=latin1", None),
+            ("application/toml", Some("toml")),
+            ("APPLICATION/TOML", Some("toml")),
+            ("text/x-toml", Some("toml")),
+            ("text/yaml", Some("yaml")),
+            ("application/x-yaml; charset=utf-16", None),
+            ("application/vnd.cargo+json", Some("json")),
+            ("application/vnd.cargo+yaml", Some("yaml")),
+            ("application/vnd.cargo+xml", None),
+            ("text/plain", None),
+            ("application/octet-stream", None),
+            ("toml", None),
+        ];
+        for (content_type, expected) in cases {
+            assert_eq!(
+                ManifestFormat::from_media_type(content_type).map(|format| format.name),
+                expected,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn sniffing_tries_stricter_formats_first() {
+        let cases = [
+            // Also valid YAML.
+            (r#"{ "package": { "name": "x" } }"#, Some("json")),
+            ("[package]\nname = \"x\"\n", Some("toml")),
+            ("name = \"x\"\n", Some("toml")),
+            ("package:\n  name: x\n", Some("yaml")),
+            // Documents that are not tables match nothing.
+            (r#""package""#, None),
+            ("[1, 2]", None),
+            // An empty TOML document is an empty table.
+            ("", Some("toml")),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(
+                ManifestFormat::sniff(body).map(|format| format.name),
+                expected,
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn wildcards_match_every_format_they_cover() {
+        assert_eq!(negotiate("*/*"), Some(SummaryFormat::Text));
+        assert_eq!(
+            negotiate("text/plain;q=0, text/*"),
+            Some(SummaryFormat::Csv)
+        );
+        assert_eq!(
+            negotiate("text/plain;q=0, text/csv;q=0, text/*"),
+            Some(SummaryFormat::Yaml)
+        );
+        assert_eq!(
+            negotiate("application/*;q=0.5, */*;q=0.1"),
+            Some(SummaryFormat::Json)
+        );
+        assert_eq!(
+            negotiate("*/*;q=0.1, application/toml;q=0.2"),
+            Some(SummaryFormat::Toml)
+        );
+        assert_eq!(negotiate("text/*;q=0, application/json;q=0"), None);
+        assert_eq!(
+            negotiate("text/*;q=0, text/csv, application/json"),
+            Some(SummaryFormat::Csv)
+        );
+    }
+
+    #[test]
+    fn overflowing_quantities_are_only_rejected_when_aggregating() {
+        // Only JSON can hold quantities beyond `i64::MAX`.
+        let manifest = format!(
+            r#"{{
+                "package": {{
+                    "name": "overflow",
+                    "keywords": ["Christmas 2024"],
+                    "metadata": {{
+                        "orders": [
+                            {{ "item": "Toy car", "quantity": {max} }},
+                            {{ "item": "Toy car", "quantity": 1 }}
+                        ]
+                    }}
+                }}
+            }}"#,
+            max = u64::MAX
+        );
+        let aggregate = ManifestParams {
+            aggregate: true,
+            ..ManifestParams::default()
+        };
+        assert_eq!(
+            rejection(summarize_with("json", &manifest, &aggregate)),
+            Some((StatusCode::BAD_REQUEST, "Total quantity too large"))
+        );
+        let Ok(summary) = summarize_as("json", &manifest) else {
+            panic!("listing the orders failed");
+        };
+        assert_eq!(summary.orders.len(), 2);
+        assert!(summary.totals.is_none());
+    }
+
+    #[test]
+    fn legacy_root_package_is_only_read_from_toml() {
+        let toml = r#"
+            name = "legacy"
+            version = "0.1.0"
+            keywords = ["Christmas 2024"]
+
+            [metadata]
+            orders = [{ item = "Toy car", quantity = 2 }]
+        "#;
+        let summary = summarize_as("toml", toml).ok().unwrap();
+        assert_eq!(summary.package.name, "legacy");
+
+        let json = r#"{
+            "name": "legacy",
+            "version": "0.1.0",
+            "keywords": ["Christmas 2024"],
+            "metadata": { "orders": [{ "item": "Toy car", "quantity": 2 }] }
+        }"#;
+        assert_eq!(
+            rejection(summarize_as("json", json)),
+            Some((StatusCode::NO_CONTENT, "Empty package"))
+        );
+        let yaml = "metadata:\n  orders: []\n";
+        assert_eq!(
+            rejection(summarize_as("yaml", yaml)),
+            Some((StatusCode::NO_CONTENT, "Empty package"))
+        );
     }
 }
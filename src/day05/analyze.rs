@@ -0,0 +1,239 @@
+//! `POST /5/analyze`: the dependencies a manifest declares and how its
+//! features enable each other.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use cargo_manifest::{Dependency, DepsSet};
+use serde::Serialize;
+
+use super::{
+    features::{FeatureScope, Reference},
+    AnyManifest, ManifestFormat,
+};
+
+#[derive(Serialize)]
+pub(super) struct DeclaredDependency {
+    pub(super) name: String,
+    /// The crate actually depended upon, when renamed with `package = "..."`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) package: Option<String>,
+    pub(super) kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) target: Option<String>,
+    /// Where the dependency comes from: `registry`, `path`, `git` or `workspace`.
+    pub(super) source: &'static str,
+    pub(super) req: String,
+    optional: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    features: Vec<String>,
+}
+
+fn source(dependency: &Dependency) -> &'static str {
+    match dependency {
+        Dependency::Simple(_) => "registry",
+        Dependency::Inherited(_) => "workspace",
+        Dependency::Detailed(detail) if detail.git.is_some() => "git",
+        Dependency::Detailed(detail) if detail.path.is_some() => "path",
+        Dependency::Detailed(_) => "registry",
+    }
+}
+
+pub(super) fn declared_dependencies(manifest: &AnyManifest) -> Vec<DeclaredDependency> {
+    let mut declared = vec![];
+    let mut declare = |kind: &'static str, target: Option<&String>, dependencies: &DepsSet| {
+        for (name, dependency) in dependencies {
+            declared.push(DeclaredDependency {
+                name: name.clone(),
+                package: dependency.package().map(str::to_string),
+                kind,
+                target: target.cloned(),
+                source: source(dependency),
+                req: dependency.req().to_string(),
+                optional: dependency.optional(),
+                features: dependency.req_features().to_vec(),
+            });
+        }
+    };
+
+    let tables = [
+        ("normal", &manifest.dependencies),
+        ("dev", &manifest.dev_dependencies),
+        ("build", &manifest.build_dependencies),
+    ];
+    for (kind, dependencies) in tables {
+        if let Some(dependencies) = dependencies {
+            declare(kind, None, dependencies);
+        }
+    }
+    for (cfg, target) in manifest.target.iter().flatten() {
+        declare("normal", Some(cfg), &target.dependencies);
+        declare("dev", Some(cfg), &target.dev_dependencies);
+        declare("build", Some(cfg), &target.build_dependencies);
+    }
+    declared
+}
+
+/// Everything switched on by enabling a feature.
+#[derive(Default, Serialize)]
+struct Enablement {
+    features: BTreeSet<String>,
+    optional_dependencies: BTreeSet<String>,
+    dependency_features: BTreeSet<String>,
+}
+
+#[derive(Serialize)]
+struct FeatureNode {
+    enables: Vec<String>,
+    /// Whether the feature only exists because of an optional dependency.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    implicit: bool,
+    transitive: Enablement,
+}
+
+#[derive(Serialize)]
+struct DanglingReference {
+    feature: String,
+    reference: String,
+    reason: String,
+}
+
+struct FeatureGraph {
+    /// Every feature, implicit ones included.
+    features: BTreeMap<String, Vec<String>>,
+    scope: FeatureScope,
+}
+
+impl FeatureGraph {
+    fn new(manifest: &AnyManifest, declared: &[DeclaredDependency]) -> Self {
+        let mut dependencies = BTreeMap::new();
+        for dependency in declared.iter().filter(|d| d.kind != "dev") {
+            *dependencies.entry(dependency.name.clone()).or_default() |= dependency.optional;
+        }
+        let mut features = manifest.features.clone().unwrap_or_default();
+        let scope = FeatureScope::new(&features, dependencies);
+        for dependency in scope.implicit() {
+            features.insert(dependency.clone(), vec![format!("dep:{dependency}")]);
+        }
+        Self { features, scope }
+    }
+
+    /// Walk the graph from `feature`, following every feature it enables.
+    fn enablement(&self, feature: &str) -> Enablement {
+        let mut enablement = Enablement::default();
+        let mut pending = vec![feature];
+        while let Some(feature) = pending.pop() {
+            for reference in self.features.get(feature).into_iter().flatten() {
+                match Reference::parse(reference) {
+                    Reference::Feature(name) => {
+                        if self.features.contains_key(name)
+                            && enablement.features.insert(name.to_string())
+                        {
+                            pending.push(name);
+                        }
+                    }
+                    Reference::Dependency(dependency) => {
+                        if self.scope.is_optional(dependency) {
+                            enablement
+                                .optional_dependencies
+                                .insert(dependency.to_string());
+                        }
+                    }
+                    Reference::DependencyFeature { dependency, weak } => {
+                        if !self.scope.is_dependency(dependency) {
+                            continue;
+                        }
+                        if !weak && self.scope.is_optional(dependency) {
+                            enablement
+                                .optional_dependencies
+                                .insert(dependency.to_string());
+                        }
+                        enablement.dependency_features.insert(reference.clone());
+                    }
+                }
+            }
+        }
+        enablement
+    }
+
+    fn dangling(&self) -> Vec<DanglingReference> {
+        let mut dangling = vec![];
+        for (feature, references) in &self.features {
+            for reference in references {
+                if let Some(reason) = self.scope.problem(reference) {
+                    dangling.push(DanglingReference {
+                        feature: feature.clone(),
+                        reference: reference.clone(),
+                        reason,
+                    });
+                }
+            }
+        }
+        dangling
+    }
+}
+
+#[derive(Serialize)]
+struct Analysis {
+    dependencies: Vec<DeclaredDependency>,
+    features: BTreeMap<String, FeatureNode>,
+    /// Optional dependencies pulled in by the `default` feature.
+    default_optional_dependencies: BTreeSet<String>,
+    dangling_references: Vec<DanglingReference>,
+}
+
+fn analyze(manifest: &AnyManifest) -> Analysis {
+    let dependencies = declared_dependencies(manifest);
+    let graph = FeatureGraph::new(manifest, &dependencies);
+
+    let features = graph
+        .features
+        .iter()
+        .map(|(feature, enables)| {
+            let node = FeatureNode {
+                enables: enables.clone(),
+                implicit: graph.scope.implicit().contains(feature),
+                transitive: graph.enablement(feature),
+            };
+            (feature.clone(), node)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let default_optional_dependencies = features
+        .get("default")
+        .map(|default| default.transitive.optional_dependencies.clone())
+        .unwrap_or_default();
+
+    Analysis {
+        dangling_references: graph.dangling(),
+        dependencies,
+        features,
+        default_optional_dependencies,
+    }
+}
+
+pub async fn analyze_manifest(header: HeaderMap, body: String) -> Response {
+    let Some(format) = header
+        .get(CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(ManifestFormat::from_media_type)
+    else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Invalid content type header".to_string(),
+        )
+            .into_response();
+    };
+    let Some(manifest) = format.parse_manifest(&body) else {
+        return (StatusCode::BAD_REQUEST, "Invalid manifest".to_string()).into_response();
+    };
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&analyze(&manifest)).unwrap(),
+    )
+        .into_response()
+}
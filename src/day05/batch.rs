@@ -0,0 +1,346 @@
+//! `POST /5/batch`: validate many manifests in one request, uploaded either
+//! as `multipart/form-data` or as a `.tar.gz` of a repository.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Multipart, Query, Request, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt};
+use mime::Mime;
+
+use super::{
+    policy::Policy, store, workspace::Report, ManifestError, ManifestFormat, ManifestParams,
+    OrderSummary,
+};
+use crate::AppState;
+
+/// Largest accepted upload, multipart or archive. Applied to the route with
+/// `DefaultBodyLimit`.
+pub const BATCH_BODY_LIMIT: usize = 16 * 1024 * 1024;
+/// Number of manifests processed at the same time.
+const BATCH_WORKERS: usize = 8;
+/// Manifests in an archive larger than this are rejected without being read.
+const MAX_MANIFEST_SIZE: u64 = 1024 * 1024;
+/// Bounds on what an archive may expand to, as a small upload can decompress
+/// to far more than [`BATCH_BODY_LIMIT`].
+const ARCHIVE_LIMITS: ArchiveLimits = ArchiveLimits {
+    size: 128 * 1024 * 1024,
+    entries: 10_000,
+};
+/// Name of the files picked out of an archive.
+const MANIFEST_FILE_NAME: &str = "Cargo.toml";
+const GZIP_MEDIA_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-tar+gzip",
+    "application/tar+gzip",
+];
+
+/// A manifest extracted from the upload, or the reason it could not be.
+type SourceFile = Result<(&'static ManifestFormat, String), ManifestError>;
+
+/// Key `file` by `path`, disambiguating repeated paths with their position.
+fn insert(files: &mut Vec<(String, SourceFile)>, path: String, file: SourceFile) {
+    let path = match files.iter().any(|(existing, _)| *existing == path) {
+        true => format!("{path}#{}", files.len()),
+        false => path,
+    };
+    files.push((path, file));
+}
+
+/// Respond to a multipart body that could not be read.
+fn multipart_rejection(status: StatusCode) -> Response {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => (status, "Batch too large").into_response(),
+        _ => (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response(),
+    }
+}
+
+async fn read_multipart(mut multipart: Multipart) -> Result<Vec<(String, SourceFile)>, Response> {
+    let mut files = vec![];
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(multipart_rejection(e.status())),
+        };
+        let format = ManifestFormat::from_part(field.content_type(), field.file_name());
+        let path = field
+            .file_name()
+            .or(field.name())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("#{}", files.len()));
+        let text = match field.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(multipart_rejection(e.status())),
+        };
+        let file = format
+            .map(|format| (format, text))
+            .ok_or(ManifestError::Rejected(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Invalid content type header",
+            ));
+        insert(&mut files, path, file);
+    }
+    Ok(files)
+}
+
+struct ArchiveLimits {
+    /// Decompressed bytes, counting every entry and header.
+    size: u64,
+    /// Entries of any kind.
+    entries: usize,
+}
+
+const ARCHIVE_TOO_LARGE: (StatusCode, &str) = (StatusCode::PAYLOAD_TOO_LARGE, "Archive too large");
+
+/// Fails every read once more than `remaining` bytes have gone through it.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)? as u64;
+        if self.exceeded || read > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other(ARCHIVE_TOO_LARGE.1));
+        }
+        self.remaining -= read;
+        Ok(read as usize)
+    }
+}
+
+/// Every `Cargo.toml` in a gzipped tarball, keyed by its path in the archive.
+fn read_archive(
+    archive: &[u8],
+    limits: &ArchiveLimits,
+) -> Result<Vec<(String, SourceFile)>, (StatusCode, &'static str)> {
+    let mut archive = tar::Archive::new(LimitedReader {
+        inner: GzDecoder::new(archive),
+        remaining: limits.size,
+        exceeded: false,
+    });
+    let files = read_entries(&mut archive, limits.entries);
+    if archive.into_inner().exceeded {
+        return Err(ARCHIVE_TOO_LARGE);
+    }
+    files
+}
+
+fn read_entries(
+    archive: &mut tar::Archive<impl Read>,
+    max_entries: usize,
+) -> Result<Vec<(String, SourceFile)>, (StatusCode, &'static str)> {
+    let invalid = |_| (StatusCode::BAD_REQUEST, "Invalid archive");
+    let mut files = vec![];
+    for (count, entry) in archive.entries().map_err(invalid)?.enumerate() {
+        if count == max_entries {
+            return Err(ARCHIVE_TOO_LARGE);
+        }
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        if !entry.header().entry_type().is_file()
+            || path.file_name() != Some(Path::new(MANIFEST_FILE_NAME).as_os_str())
+        {
+            continue;
+        }
+        let path = path.to_string_lossy().into_owned();
+        let file = if entry.size() > MAX_MANIFEST_SIZE {
+            Err(ManifestError::Rejected(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Manifest too large",
+            ))
+        } else {
+            let mut text = String::new();
+            match entry.read_to_string(&mut text) {
+                Ok(_) => Ok((ManifestFormat::from_name("toml").unwrap(), text)),
+                Err(_) => Err(ManifestError::Rejected(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid manifest",
+                )),
+            }
+        };
+        insert(&mut files, path, file);
+    }
+    Ok(files)
+}
+
+async fn process_file(
+    path: String,
+    file: SourceFile,
+    params: Arc<ManifestParams>,
+) -> (String, Result<OrderSummary, ManifestError>) {
+    let result = match file {
+        Ok((format, text)) => {
+            tokio::task::spawn_blocking(move || super::process(format, &text, &params))
+                .await
+                .unwrap_or(Err(ManifestError::Rejected(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to process manifest",
+                )))
+        }
+        Err(e) => Err(e),
+    };
+    (path, result)
+}
+
+/// Process every manifest of a multipart or `.tar.gz` upload, reporting a
+/// result per file keyed by its path.
+pub async fn batch(
+    State(state): State<AppState>,
+    Query(params): Query<ManifestParams>,
+    request: Request,
+) -> Response {
+    if let Err(rejection) = Policy::from_name(params.policy.as_deref()) {
+        return rejection.into_response();
+    }
+    let pool = match params.store {
+        true => Some(state.read().await.pool.clone()),
+        false => None,
+    };
+
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(|content_type| content_type.parse::<Mime>().ok());
+    let files = match content_type {
+        Some(mime) if mime.essence_str() == "multipart/form-data" => {
+            let Ok(multipart) = Multipart::from_request(request, &()).await else {
+                return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response();
+            };
+            match read_multipart(multipart).await {
+                Ok(files) => files,
+                Err(response) => return response,
+            }
+        }
+        Some(mime) if GZIP_MEDIA_TYPES.contains(&mime.essence_str()) => {
+            let body = match Bytes::from_request(request, &()).await {
+                Ok(body) => body,
+                Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                    return (StatusCode::PAYLOAD_TOO_LARGE, "Batch too large").into_response()
+                }
+                Err(_) => return (StatusCode::BAD_REQUEST, "Failed to read body").into_response(),
+            };
+            match tokio::task::spawn_blocking(move || read_archive(&body, &ARCHIVE_LIMITS)).await {
+                Ok(Ok(files)) => files,
+                Ok(Err(rejection)) => return rejection.into_response(),
+                Err(_) => return (StatusCode::BAD_REQUEST, "Invalid archive").into_response(),
+            }
+        }
+        _ => {
+            return (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Invalid content type header",
+            )
+                .into_response()
+        }
+    };
+
+    let params = Arc::new(params);
+    let tasks = files
+        .into_iter()
+        .map(|(path, file)| process_file(path, file, params.clone()))
+        .collect::<Vec<_>>();
+    let results = stream::iter(tasks)
+        .buffer_unordered(BATCH_WORKERS)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut reports = BTreeMap::new();
+    for (path, mut result) in results {
+        if let (Some(pool), Ok(summary)) = (&pool, &result) {
+            if store::store_summary(pool, summary).await.is_err() {
+                result = Err(ManifestError::Rejected(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to store orders",
+                ));
+            }
+        }
+        reports.insert(path, Report::new(result));
+    }
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        serde_json::json!({ "files": reports }).to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const LIMITS: ArchiveLimits = ArchiveLimits {
+        size: 64 * 1024,
+        entries: 4,
+    };
+
+    /// A gzipped tarball holding a file of `size` zeros under each path.
+    fn archive(files: &[(&str, usize)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        for &(path, size) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(size as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, io::repeat(0).take(size as u64))
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn paths(archive: &[u8]) -> Result<Vec<String>, (StatusCode, &'static str)> {
+        let files = read_archive(archive, &LIMITS)?;
+        Ok(files.into_iter().map(|(path, _)| path).collect())
+    }
+
+    #[test]
+    fn only_manifests_are_read() {
+        let archive = archive(&[("Cargo.toml", 10), ("README.md", 10), ("a/Cargo.toml", 10)]);
+        assert_eq!(
+            paths(&archive),
+            Ok(vec!["Cargo.toml".to_string(), "a/Cargo.toml".to_string()])
+        );
+    }
+
+    #[test]
+    fn archives_expanding_too_far_are_refused() {
+        // A few hundred bytes compressed, well over the limit expanded.
+        let archive = archive(&[("README.md", 1024 * 1024), ("Cargo.toml", 10)]);
+        assert!(archive.len() < 16 * 1024);
+        assert_eq!(paths(&archive), Err(ARCHIVE_TOO_LARGE));
+    }
+
+    #[test]
+    fn archives_with_too_many_entries_are_refused() {
+        let files = ["a", "b", "c", "d"].map(|name| (name, 0));
+        assert!(paths(&archive(&files)).is_ok());
+        let files = ["a", "b", "c", "d", "e"].map(|name| (name, 0));
+        assert_eq!(paths(&archive(&files)), Err(ARCHIVE_TOO_LARGE));
+    }
+
+    #[test]
+    fn garbage_is_not_an_archive() {
+        assert_eq!(
+            paths(b"not gzip"),
+            Err((StatusCode::BAD_REQUEST, "Invalid archive"))
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! `POST /5/compat`: check a manifest's version, `rust-version` and
+//! dependency requirements, the latter against a client-supplied snapshot of
+//! the crate index.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Multipart, Query},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use cargo_manifest::MaybeInherited;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use super::{analyze::declared_dependencies, AnyManifest, ManifestFormat};
+
+/// Multipart field holding the manifest.
+const MANIFEST_FIELD: &str = "manifest";
+/// Multipart field holding the index snapshot: one crates.io index entry
+/// (`{"name": ..., "vers": ..., "yanked": ...}`) per line.
+const INDEX_FIELD: &str = "index";
+
+#[derive(Deserialize)]
+pub struct CompatParams {
+    toolchain: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+    vers: Version,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Published versions by crate name, each with whether it was yanked.
+type Index = HashMap<String, Vec<(Version, bool)>>;
+
+fn parse_index(snapshot: &str) -> Option<Index> {
+    let mut index = Index::new();
+    for line in snapshot.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: IndexEntry = serde_json::from_str(line).ok()?;
+        index
+            .entry(entry.name)
+            .or_default()
+            .push((entry.vers, entry.yanked));
+    }
+    Some(index)
+}
+
+/// Parse a Rust version such as `1.75` or `1.75.0`.
+pub(super) fn parse_rust_version(rust_version: &str) -> Option<Version> {
+    let components = rust_version
+        .split('.')
+        // `parse` would also take a leading sign.
+        .map(
+            |component| match component.bytes().all(|b| b.is_ascii_digit()) {
+                true => component.parse::<u64>().ok(),
+                false => None,
+            },
+        )
+        .collect::<Option<Vec<_>>>()?;
+    match components[..] {
+        [major, minor] => Some(Version::new(major, minor, 0)),
+        [major, minor, patch] => Some(Version::new(major, minor, patch)),
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum VersionCheck {
+    Valid { version: String },
+    Invalid { version: String, error: String },
+    Inherited,
+    Missing,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum ToolchainCheck {
+    Compatible {
+        required: String,
+        toolchain: String,
+    },
+    Incompatible {
+        required: String,
+        toolchain: String,
+    },
+    InvalidRustVersion {
+        required: String,
+    },
+    /// No `rust-version` to check, or no toolchain given.
+    Unchecked,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum RequirementCheck {
+    /// `resolved` is the newest non-yanked version matching the requirement.
+    Satisfied {
+        resolved: String,
+    },
+    /// Only yanked versions match the requirement.
+    Yanked {
+        versions: Vec<String>,
+    },
+    Unsatisfiable,
+    UnknownCrate,
+    InvalidRequirement {
+        error: String,
+    },
+    /// Not from the registry, or no index was supplied.
+    Unchecked,
+}
+
+impl RequirementCheck {
+    fn evaluate(req: &str, versions: Option<&Vec<(Version, bool)>>) -> Self {
+        let req = match VersionReq::parse(req) {
+            Ok(req) => req,
+            Err(e) => {
+                return Self::InvalidRequirement {
+                    error: e.to_string(),
+                }
+            }
+        };
+        let Some(versions) = versions else {
+            return Self::UnknownCrate;
+        };
+        let matching = versions
+            .iter()
+            .filter(|(version, _)| req.matches(version))
+            .collect::<Vec<_>>();
+        match matching
+            .iter()
+            .filter(|(_, yanked)| !yanked)
+            .map(|(version, _)| version)
+            .max()
+        {
+            Some(resolved) => Self::Satisfied {
+                resolved: resolved.to_string(),
+            },
+            None if matching.is_empty() => Self::Unsatisfiable,
+            None => Self::Yanked {
+                versions: matching
+                    .iter()
+                    .map(|(version, _)| version.to_string())
+                    .collect(),
+            },
+        }
+    }
+
+    fn is_problem(&self) -> bool {
+        !matches!(self, Self::Satisfied { .. } | Self::Unchecked)
+    }
+}
+
+#[derive(Serialize)]
+struct DependencyReport {
+    name: String,
+    #[serde(rename = "crate")]
+    krate: String,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    req: String,
+    #[serde(flatten)]
+    check: RequirementCheck,
+}
+
+#[derive(Serialize)]
+struct CompatReport {
+    compatible: bool,
+    version: VersionCheck,
+    rust_version: ToolchainCheck,
+    dependencies: Vec<DependencyReport>,
+}
+
+fn check(
+    manifest: &AnyManifest,
+    toolchain: Option<&Version>,
+    index: Option<&Index>,
+) -> CompatReport {
+    let package = manifest.package.as_ref();
+
+    let version = match package.and_then(|package| package.version.as_ref()) {
+        Some(MaybeInherited::Local(version)) => match Version::parse(version) {
+            Ok(_) => VersionCheck::Valid {
+                version: version.clone(),
+            },
+            Err(e) => VersionCheck::Invalid {
+                version: version.clone(),
+                error: e.to_string(),
+            },
+        },
+        Some(MaybeInherited::Inherited { .. }) => VersionCheck::Inherited,
+        None => VersionCheck::Missing,
+    };
+
+    let rust_version = match (
+        package.and_then(|package| package.rust_version.as_ref()),
+        toolchain,
+    ) {
+        (Some(MaybeInherited::Local(required)), Some(toolchain)) => {
+            match parse_rust_version(required) {
+                Some(minimum) if minimum <= *toolchain => ToolchainCheck::Compatible {
+                    required: required.clone(),
+                    toolchain: toolchain.to_string(),
+                },
+                Some(_) => ToolchainCheck::Incompatible {
+                    required: required.clone(),
+                    toolchain: toolchain.to_string(),
+                },
+                None => ToolchainCheck::InvalidRustVersion {
+                    required: required.clone(),
+                },
+            }
+        }
+        _ => ToolchainCheck::Unchecked,
+    };
+
+    let dependencies = declared_dependencies(manifest)
+        .into_iter()
+        .map(|dependency| {
+            let krate = dependency
+                .package
+                .unwrap_or_else(|| dependency.name.clone());
+            let check = match index {
+                Some(index) if dependency.source == "registry" => {
+                    RequirementCheck::evaluate(&dependency.req, index.get(&krate))
+                }
+                _ => RequirementCheck::Unchecked,
+            };
+            DependencyReport {
+                name: dependency.name,
+                krate,
+                kind: dependency.kind,
+                target: dependency.target,
+                req: dependency.req,
+                check,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let compatible = matches!(
+        version,
+        VersionCheck::Valid { .. } | VersionCheck::Inherited
+    ) && !matches!(
+        rust_version,
+        ToolchainCheck::Incompatible { .. } | ToolchainCheck::InvalidRustVersion { .. }
+    ) && !dependencies
+        .iter()
+        .any(|dependency| dependency.check.is_problem());
+
+    CompatReport {
+        compatible,
+        version,
+        rust_version,
+        dependencies,
+    }
+}
+
+/// Takes `multipart/form-data` with a `manifest` field and an optional
+/// `index` field; dependencies are only checked when an index is supplied.
+pub async fn compat(Query(params): Query<CompatParams>, mut multipart: Multipart) -> Response {
+    let toolchain = match params.toolchain.as_deref().map(parse_rust_version) {
+        Some(Some(toolchain)) => Some(toolchain),
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid toolchain").into_response(),
+        None => None,
+    };
+
+    let mut manifest = None;
+    let mut index = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response(),
+        };
+        match field.name() {
+            Some(MANIFEST_FIELD) => {
+                let format = ManifestFormat::from_part(field.content_type(), field.file_name());
+                let Ok(text) = field.text().await else {
+                    return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response();
+                };
+                let Some(parsed) = format.and_then(|format| format.parse_manifest(&text)) else {
+                    return (StatusCode::BAD_REQUEST, "Invalid manifest").into_response();
+                };
+                manifest = Some(parsed);
+            }
+            Some(INDEX_FIELD) => {
+                let Ok(text) = field.text().await else {
+                    return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response();
+                };
+                let Some(parsed) = parse_index(&text) else {
+                    return (StatusCode::BAD_REQUEST, "Invalid index").into_response();
+                };
+                index = Some(parsed);
+            }
+            _ => (),
+        }
+    }
+    let Some(manifest) = manifest else {
+        return (StatusCode::BAD_REQUEST, "Missing manifest").into_response();
+    };
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        serde_json::to_string(&check(&manifest, toolchain.as_ref(), index.as_ref())).unwrap(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const INDEX: &str = r#"
+        {"name": "serde", "vers": "1.0.100"}
+        {"name": "serde", "vers": "1.0.200"}
+        {"name": "serde", "vers": "1.0.201", "yanked": true}
+        {"name": "old", "vers": "0.1.0", "yanked": true}
+        {"name": "old", "vers": "0.1.1", "yanked": true}
+        {"name": "beta", "vers": "2.0.0-beta.1"}
+    "#;
+
+    fn evaluate(krate: &str, req: &str) -> Value {
+        let index = parse_index(INDEX).unwrap();
+        serde_json::to_value(RequirementCheck::evaluate(req, index.get(krate))).unwrap()
+    }
+
+    fn compatible(manifest: &str, toolchain: Option<&str>, index: Option<&str>) -> bool {
+        let manifest: AnyManifest = toml::from_str(manifest).unwrap();
+        let toolchain = toolchain.map(|toolchain| parse_rust_version(toolchain).unwrap());
+        let index = index.map(|index| parse_index(index).unwrap());
+        check(&manifest, toolchain.as_ref(), index.as_ref()).compatible
+    }
+
+    #[test]
+    fn rust_versions_have_two_or_three_numeric_components() {
+        assert_eq!(parse_rust_version("1.75"), Some(Version::new(1, 75, 0)));
+        assert_eq!(parse_rust_version("1.75.2"), Some(Version::new(1, 75, 2)));
+        assert_eq!(parse_rust_version("1"), None);
+        assert_eq!(parse_rust_version("1.75.0.1"), None);
+        assert_eq!(parse_rust_version("1.+75"), None);
+        assert_eq!(parse_rust_version("1..0"), None);
+        assert_eq!(parse_rust_version("1.75.0-nightly"), None);
+        assert_eq!(parse_rust_version(""), None);
+    }
+
+    #[test]
+    fn requirements_resolve_to_the_newest_unyanked_version() {
+        assert_eq!(
+            evaluate("serde", "1.0"),
+            json!({ "status": "satisfied", "resolved": "1.0.200" })
+        );
+        assert_eq!(
+            evaluate("serde", "=1.0.100"),
+            json!({ "status": "satisfied", "resolved": "1.0.100" })
+        );
+        assert_eq!(
+            evaluate("serde", "=1.0.201"),
+            json!({ "status": "yanked", "versions": ["1.0.201"] })
+        );
+        assert_eq!(
+            evaluate("old", "0.1"),
+            json!({ "status": "yanked", "versions": ["0.1.0", "0.1.1"] })
+        );
+        assert_eq!(evaluate("serde", "2"), json!({ "status": "unsatisfiable" }));
+        assert_eq!(
+            evaluate("missing", "1"),
+            json!({ "status": "unknown-crate" })
+        );
+        assert_eq!(
+            evaluate("serde", "not a requirement")["status"],
+            "invalid-requirement"
+        );
+    }
+
+    #[test]
+    fn pre_releases_only_match_requirements_that_name_them() {
+        assert_eq!(evaluate("beta", "2"), json!({ "status": "unsatisfiable" }));
+        assert_eq!(
+            evaluate("beta", "2.0.0-beta"),
+            json!({ "status": "satisfied", "resolved": "2.0.0-beta.1" })
+        );
+    }
+
+    #[test]
+    fn manifests_are_compatible_only_without_problems() {
+        let manifest = r#"
+            [package]
+            name = "demo"
+            version = "0.1.0"
+            rust-version = "1.70"
+
+            [dependencies]
+            serde = "1"
+            local = { path = "../local" }
+        "#;
+        assert!(compatible(manifest, Some("1.75"), Some(INDEX)));
+        assert!(compatible(manifest, None, None));
+        assert!(!compatible(manifest, Some("1.69.9"), Some(INDEX)));
+        assert!(!compatible(
+            manifest,
+            None,
+            Some(r#"{"name": "serde", "vers": "0.9.0"}"#)
+        ));
+
+        let without_version = "[package]\nname = \"demo\"\n";
+        assert!(!compatible(without_version, None, None));
+        let invalid_version = "[package]\nname = \"demo\"\nversion = \"1\"\n";
+        assert!(!compatible(invalid_version, None, None));
+        let inherited = "[package]\nname = \"demo\"\nversion.workspace = true\n";
+        assert!(compatible(inherited, None, None));
+        let invalid_rust_version =
+            "[package]\nname = \"demo\"\nversion = \"1.0.0\"\nrust-version = \"1\"\n";
+        assert!(!compatible(invalid_rust_version, Some("1.75"), None));
+    }
+}
@@ -0,0 +1,437 @@
+//! `POST /5/convert`: translate a manifest between the supported formats.
+
+use axum::{
+    extract::Query,
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use serde_json::Value;
+use toml_edit::{
+    visit_mut::{self, VisitMut},
+    Array, DocumentMut, InlineTable, Item, KeyMut, RawString, Table,
+};
+
+use super::ManifestFormat;
+
+/// Key under which `toml` represents datetimes when deserializing into a
+/// format-agnostic value.
+const TOML_DATETIME_KEY: &str = "$__toml_private_datetime";
+
+#[derive(Deserialize)]
+pub struct ConvertParams {
+    to: String,
+}
+
+/// Something in the source document that the target format cannot carry.
+#[derive(Serialize)]
+struct LostField {
+    path: String,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct Conversion {
+    from: &'static str,
+    to: &'static str,
+    output: String,
+    lost: Vec<LostField>,
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Rewrite `value` into something `target` can serialize, recording what had
+/// to be dropped or changed along the way.
+fn prepare(value: &mut Value, target: &ManifestFormat, path: &str, lost: &mut Vec<LostField>) {
+    match value {
+        Value::Object(map)
+            if !target.datetimes && map.len() == 1 && map.contains_key(TOML_DATETIME_KEY) =>
+        {
+            *value = map.remove(TOML_DATETIME_KEY).unwrap();
+            lost.push(LostField {
+                path: path.to_string(),
+                reason: "TOML datetime converted to a string",
+            });
+        }
+        Value::Object(map) => {
+            map.retain(|key, value| {
+                let child = child_path(path, key);
+                match (target.unrepresentable)(value) {
+                    None => true,
+                    Some(reason) => {
+                        lost.push(LostField {
+                            path: child,
+                            reason,
+                        });
+                        false
+                    }
+                }
+            });
+            for (key, value) in map.iter_mut() {
+                prepare(value, target, &child_path(path, key), lost);
+            }
+        }
+        Value::Array(values) => {
+            let mut index = 0;
+            values.retain(|value| {
+                let keep = match (target.unrepresentable)(value) {
+                    None => true,
+                    Some(reason) => {
+                        lost.push(LostField {
+                            path: format!("{path}[{index}]"),
+                            reason,
+                        });
+                        false
+                    }
+                };
+                index += 1;
+                keep
+            });
+            for (index, value) in values.iter_mut().enumerate() {
+                prepare(value, target, &format!("{path}[{index}]"), lost);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Why TOML cannot hold `value`, if it cannot.
+pub(super) fn toml_unrepresentable(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Null => Some("TOML has no null value"),
+        Value::Number(number) if number.is_u64() && !number.is_i64() => {
+            Some("Integer out of range for TOML")
+        }
+        _ => None,
+    }
+}
+
+/// Keep only the comment lines of a decor prefix, unindented, with runs of
+/// blank lines collapsed to one. The text after the last newline is the
+/// indentation of what follows, and is dropped.
+fn normalize_prefix(raw: &str) -> String {
+    let mut lines = raw.split('\n').collect::<Vec<_>>();
+    lines.pop();
+    let mut prefix = String::new();
+    let mut blank = false;
+    for line in lines.iter().map(|line| line.trim()) {
+        if line.starts_with('#') {
+            prefix.push_str(line);
+            prefix.push('\n');
+            blank = false;
+        } else if !blank {
+            prefix.push('\n');
+            blank = true;
+        }
+    }
+    prefix
+}
+
+/// A trailing comment, if any, one space after what it follows.
+fn normalize_suffix(raw: &str) -> String {
+    match raw.trim() {
+        comment if comment.starts_with('#') => format!(" {comment}"),
+        _ => String::new(),
+    }
+}
+
+/// Decor as written, or empty when it is implicit.
+fn raw(raw: Option<&RawString>) -> &str {
+    raw.and_then(RawString::as_str).unwrap_or_default()
+}
+
+/// Normalises the layout of a TOML document while keeping its comments and
+/// key order: `key = value` spacing, unindented keys and headers, one blank
+/// line at most between groups, and single-line arrays and inline tables
+/// unless they hold comments.
+#[derive(Default)]
+struct Normalize {
+    /// Whether the pairs visited are in an inline table, which lays them out
+    /// itself.
+    inline: bool,
+}
+
+impl VisitMut for Normalize {
+    fn visit_table_mut(&mut self, node: &mut Table) {
+        let decor = node.decor_mut();
+        let prefix = normalize_prefix(raw(decor.prefix()));
+        let suffix = normalize_suffix(raw(decor.suffix()));
+        decor.set_prefix(match prefix.starts_with('\n') {
+            true => prefix,
+            false => format!("\n{prefix}"),
+        });
+        decor.set_suffix(suffix);
+        visit_mut::visit_table_mut(self, node);
+    }
+
+    fn visit_table_like_kv_mut(&mut self, mut key: KeyMut<'_>, node: &mut Item) {
+        if self.inline {
+            return visit_mut::visit_table_like_kv_mut(self, key, node);
+        }
+        let leaf = key.leaf_decor_mut();
+        match node {
+            Item::Value(value) => {
+                let prefix = normalize_prefix(raw(leaf.prefix()));
+                leaf.set_prefix(prefix);
+                leaf.set_suffix(" ");
+                let decor = value.decor_mut();
+                let suffix = normalize_suffix(raw(decor.suffix()));
+                decor.set_prefix(" ");
+                decor.set_suffix(suffix);
+            }
+            // Dotted keys keep the comments above them; headers have none.
+            Item::Table(table) if table.is_dotted() => {
+                let prefix = normalize_prefix(raw(leaf.prefix()));
+                leaf.set_prefix(prefix);
+                leaf.set_suffix("");
+            }
+            _ => leaf.clear(),
+        }
+        key.dotted_decor_mut().clear();
+        visit_mut::visit_table_like_kv_mut(self, key, node);
+    }
+
+    fn visit_inline_table_mut(&mut self, node: &mut InlineTable) {
+        // Inline tables cannot hold comments.
+        node.fmt();
+        let inline = std::mem::replace(&mut self.inline, true);
+        visit_mut::visit_inline_table_mut(self, node);
+        self.inline = inline;
+    }
+
+    fn visit_array_mut(&mut self, node: &mut Array) {
+        let commented = raw(Some(node.trailing())).contains('#')
+            || node.iter().any(|value| {
+                let decor = value.decor();
+                raw(decor.prefix()).contains('#') || raw(decor.suffix()).contains('#')
+            });
+        if !commented {
+            node.fmt();
+        }
+        visit_mut::visit_array_mut(self, node);
+    }
+}
+
+/// Rewrite a TOML document with [`Normalize`].
+pub(super) fn normalize_toml(body: &str) -> Result<String, String> {
+    let mut document = body.parse::<DocumentMut>().map_err(|e| e.to_string())?;
+    Normalize::default().visit_document_mut(&mut document);
+    let trailing = normalize_prefix(&format!("{}\n", raw(Some(document.trailing()))));
+    document.set_trailing(trailing.trim_start_matches('\n'));
+    let output = document.to_string();
+    Ok(format!("{}\n", output.trim()))
+}
+
+/// Just enough of a document to find the floats the common JSON model turns
+/// into `null`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(super) enum FloatTree {
+    Float(f64),
+    Array(Vec<FloatTree>),
+    Table(BTreeMap<String, FloatTree>),
+    Other(IgnoredAny),
+}
+
+impl FloatTree {
+    fn non_finite(&self, path: &str, paths: &mut Vec<String>) {
+        match self {
+            Self::Float(float) if !float.is_finite() => paths.push(path.to_string()),
+            Self::Array(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    value.non_finite(&format!("{path}[{index}]"), paths);
+                }
+            }
+            Self::Table(table) => {
+                for (key, value) in table {
+                    value.non_finite(&child_path(path, key), paths);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Paths of the NaN and infinite floats in a document read as a
+/// [`FloatTree`], if it could be.
+pub(super) fn non_finite_floats(tree: Option<FloatTree>) -> Vec<String> {
+    let mut paths = vec![];
+    if let Some(tree) = tree {
+        tree.non_finite("", &mut paths);
+    }
+    paths
+}
+
+const NON_FINITE_FLOAT: &str = "NaN and infinite floats are not carried over";
+
+/// Comments are only carried over by [`ManifestFormat::normalize`].
+fn has_comments(source: &ManifestFormat, body: &str) -> bool {
+    source.comment.is_some_and(|marker| {
+        body.lines()
+            .any(|line| line.trim_start().starts_with(marker))
+    })
+}
+
+fn convert_document(
+    source: &'static ManifestFormat,
+    target: &'static ManifestFormat,
+    body: &str,
+) -> Result<Conversion, (StatusCode, String)> {
+    let invalid = |e: String| (StatusCode::BAD_REQUEST, format!("Invalid manifest: {e}"));
+    let mut document = (source.parse)(body).map_err(invalid)?;
+    if source.manifest_from_document(document.clone()).is_none() {
+        return Err(invalid("not a Cargo manifest".to_string()));
+    }
+
+    // Converting to the same format keeps comments where it can, as TOML
+    // does through `toml_edit`.
+    if let Some(normalize) = target.normalize.filter(|_| source.name == target.name) {
+        return Ok(Conversion {
+            from: source.name,
+            to: target.name,
+            output: normalize(body).map_err(invalid)?,
+            lost: vec![],
+        });
+    }
+
+    let mut lost = vec![];
+    if has_comments(source, body) {
+        lost.push(LostField {
+            path: String::new(),
+            reason: "Comments are not preserved",
+        });
+    }
+    let non_finite = (source.non_finite_floats)(body);
+    lost.extend(non_finite.iter().map(|path| LostField {
+        path: path.clone(),
+        reason: NON_FINITE_FLOAT,
+    }));
+    prepare(&mut document, target, "", &mut lost);
+    // Such floats were parsed as `null`, which is not worth a second report.
+    lost.retain(|field| field.reason == NON_FINITE_FLOAT || !non_finite.contains(&field.path));
+    let output = (target.serialize)(&document).map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Failed to convert to {}: {e}", target.name),
+        )
+    })?;
+    Ok(Conversion {
+        from: source.name,
+        to: target.name,
+        output,
+        lost,
+    })
+}
+
+/// Convert the manifest in the body to the format named by `to`, preserving
+/// key order. The response carries the converted text along with everything
+/// the target format could not represent.
+pub async fn convert(
+    Query(params): Query<ConvertParams>,
+    header: HeaderMap,
+    body: String,
+) -> Response {
+    let Some(source) = header
+        .get(CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(ManifestFormat::from_media_type)
+    else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Invalid content type header".to_string(),
+        )
+            .into_response();
+    };
+    let Some(target) = ManifestFormat::from_name(&params.to) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Unsupported target format `{}`", params.to),
+        )
+            .into_response();
+    };
+
+    match convert_document(source, target, &body) {
+        Ok(conversion) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, "application/json")],
+            serde_json::to_string(&conversion).unwrap(),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_is_normalized_keeping_comments() {
+        let input = concat!(
+            "# The package\n\n\n",
+            "  [ package ]   # header comment\n",
+            "    name=\"demo\"\n",
+            "# the version\n",
+            "  version   =   \"0.1.0\"    # semver\n",
+            "keywords = [ \"a\" ,\n  \"b\" ]\n",
+            "authors = [\n  \"x\", # first\n]\n",
+            "metadata . orders = { item  =  \"toy\" , quantity=1 }\n",
+            "[dependencies]\n",
+            "serde = \"1\"\n\n\n\n",
+            "[[bin]]\n",
+            "name = \"x\"\n",
+            "# trailing comment\n\n",
+        );
+        let expected = concat!(
+            "# The package\n\n",
+            "[package] # header comment\n",
+            "name = \"demo\"\n",
+            "# the version\n",
+            "version = \"0.1.0\" # semver\n",
+            "keywords = [\"a\", \"b\"]\n",
+            "authors = [\n  \"x\", # first\n]\n",
+            "metadata.orders = { item = \"toy\", quantity = 1 }\n\n",
+            "[dependencies]\n",
+            "serde = \"1\"\n\n",
+            "[[bin]]\n",
+            "name = \"x\"\n",
+            "# trailing comment\n",
+        );
+        assert_eq!(normalize_toml(input).unwrap(), expected);
+        assert_eq!(normalize_toml(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn non_finite_floats_are_reported_lost() {
+        let yaml = ManifestFormat::from_name("yaml").unwrap();
+        let json = ManifestFormat::from_name("json").unwrap();
+        let toml = ManifestFormat::from_name("toml").unwrap();
+        let body =
+            "package:\n  name: demo\n  metadata:\n    ratio: .nan\n    limits: [1.5, -.inf]\n";
+        for target in [json, toml] {
+            let conversion = convert_document(yaml, target, body).ok().unwrap();
+            let lost = conversion
+                .lost
+                .iter()
+                .map(|field| (field.path.as_str(), field.reason))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                lost,
+                [
+                    ("package.metadata.limits[1]", NON_FINITE_FLOAT),
+                    ("package.metadata.ratio", NON_FINITE_FLOAT),
+                ],
+                "{}",
+                target.name
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! How the entries of a `[features]` table resolve, shared by `/5/lint` and
+//! `/5/analyze`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A single entry in a feature's list.
+pub(super) enum Reference<'a> {
+    /// `name`: another feature, or the implicit feature of an optional dependency.
+    Feature(&'a str),
+    /// `dep:name`
+    Dependency(&'a str),
+    /// `name/feature`, or `name?/feature` when `weak`.
+    DependencyFeature { dependency: &'a str, weak: bool },
+}
+
+impl<'a> Reference<'a> {
+    pub(super) fn parse(reference: &'a str) -> Self {
+        if let Some(dependency) = reference.strip_prefix("dep:") {
+            Self::Dependency(dependency)
+        } else if let Some((dependency, _)) = reference.split_once('/') {
+            match dependency.strip_suffix('?') {
+                Some(dependency) => Self::DependencyFeature {
+                    dependency,
+                    weak: true,
+                },
+                None => Self::DependencyFeature {
+                    dependency,
+                    weak: false,
+                },
+            }
+        } else {
+            Self::Feature(reference)
+        }
+    }
+}
+
+/// Everything a manifest's features may refer to.
+pub(super) struct FeatureScope {
+    features: BTreeSet<String>,
+    /// Dependencies features may refer to (everything but dev-dependencies),
+    /// mapped to whether they are optional.
+    dependencies: BTreeMap<String, bool>,
+    /// Optional dependencies that get a feature of the same name.
+    implicit: BTreeSet<String>,
+}
+
+impl FeatureScope {
+    pub(super) fn new(
+        features: &BTreeMap<String, Vec<String>>,
+        dependencies: BTreeMap<String, bool>,
+    ) -> Self {
+        // Optional dependencies only get an implicit feature if no feature
+        // refers to them with `dep:`.
+        let explicit = features
+            .values()
+            .flatten()
+            .filter_map(|reference| reference.strip_prefix("dep:"))
+            .collect::<BTreeSet<_>>();
+        let implicit = dependencies
+            .iter()
+            .filter(|(dependency, &optional)| {
+                optional
+                    && !explicit.contains(dependency.as_str())
+                    && !features.contains_key(*dependency)
+            })
+            .map(|(dependency, _)| dependency.clone())
+            .collect();
+        Self {
+            features: features.keys().cloned().collect(),
+            dependencies,
+            implicit,
+        }
+    }
+
+    pub(super) fn implicit(&self) -> &BTreeSet<String> {
+        &self.implicit
+    }
+
+    pub(super) fn is_feature(&self, name: &str) -> bool {
+        self.features.contains(name) || self.implicit.contains(name)
+    }
+
+    pub(super) fn is_dependency(&self, dependency: &str) -> bool {
+        self.dependencies.contains_key(dependency)
+    }
+
+    pub(super) fn is_optional(&self, dependency: &str) -> bool {
+        self.dependencies.get(dependency) == Some(&true)
+    }
+
+    /// Why `reference` resolves to nothing, if it doesn't.
+    pub(super) fn problem(&self, reference: &str) -> Option<String> {
+        match Reference::parse(reference) {
+            Reference::Feature(name) => (!self.is_feature(name))
+                .then(|| format!("`{name}` is neither a feature nor an optional dependency")),
+            Reference::Dependency(dependency) => match self.dependencies.get(dependency) {
+                Some(true) => None,
+                Some(false) => Some(format!("`{dependency}` is not an optional dependency")),
+                None => Some(format!("`{dependency}` is not a dependency")),
+            },
+            Reference::DependencyFeature { dependency, .. } => (!self.is_dependency(dependency))
+                .then(|| format!("`{dependency}` is not a dependency")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_resolve_against_features_and_dependencies() {
+        let features = BTreeMap::from([
+            ("default".to_string(), vec!["std".to_string()]),
+            ("std".to_string(), vec!["dep:serde".to_string()]),
+        ]);
+        let dependencies = BTreeMap::from([
+            ("serde".to_string(), true),
+            ("rand".to_string(), true),
+            ("regex".to_string(), false),
+        ]);
+        let scope = FeatureScope::new(&features, dependencies);
+
+        // `serde` is referred to with `dep:`, so only `rand` is implicit.
+        assert_eq!(scope.implicit().iter().collect::<Vec<_>>(), ["rand"]);
+        for reference in ["std", "rand", "dep:serde", "regex/unicode", "serde?/derive"] {
+            assert_eq!(scope.problem(reference), None, "{reference}");
+        }
+        assert_eq!(
+            scope.problem("serde").as_deref(),
+            Some("`serde` is neither a feature nor an optional dependency")
+        );
+        assert_eq!(
+            scope.problem("dep:regex").as_deref(),
+            Some("`regex` is not an optional dependency")
+        );
+        assert_eq!(
+            scope.problem("tokio/rt").as_deref(),
+            Some("`tokio` is not a dependency")
+        );
+    }
+}
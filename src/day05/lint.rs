@@ -0,0 +1,462 @@
+//! `POST /5/lint`: report every problem found in a `Cargo.toml`, with the
+//! location of each in the original source.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+};
+
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+use toml_edit::{ImDocument, Item, TableLike};
+
+use super::{compat::parse_rust_version, features::FeatureScope};
+
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+const DEPENDENCY_TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+/// 1-based line and column (in characters).
+#[derive(Serialize)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Serialize)]
+struct Span {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Serialize)]
+struct Issue {
+    code: &'static str,
+    severity: Severity,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<Span>,
+}
+
+struct Linter<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+    issues: Vec<Issue>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+            issues: vec![],
+        }
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..offset.min(self.source.len())]
+            .chars()
+            .count();
+        Position {
+            line: line + 1,
+            column: column + 1,
+        }
+    }
+
+    fn report(
+        &mut self,
+        code: &'static str,
+        severity: Severity,
+        message: String,
+        span: Option<Range<usize>>,
+    ) {
+        let span = span.map(|span| Span {
+            start: self.position(span.start),
+            end: self.position(span.end),
+        });
+        self.issues.push(Issue {
+            code,
+            severity,
+            message,
+            span,
+        });
+    }
+
+    /// Span of the value stored under `key`, falling back to the key itself.
+    fn span_of(table: &dyn TableLike, key: &str) -> Option<Range<usize>> {
+        let (key, item) = table.get_key_value(key)?;
+        item.span().or_else(|| key.span())
+    }
+
+    /// A string field of `[package]`. Inherited (`field.workspace = true`) and
+    /// absent fields yield `None`; non-string values are reported.
+    fn package_str<'d>(&mut self, package: &'d dyn TableLike, key: &str) -> Option<&'d str> {
+        let item = package.get(key)?;
+        if let Some(value) = item.as_str() {
+            return Some(value);
+        }
+        let inherited = item
+            .as_table_like()
+            .and_then(|table| table.get("workspace"))
+            .and_then(Item::as_bool)
+            == Some(true);
+        if !inherited {
+            self.report(
+                "invalid-type",
+                Severity::Error,
+                format!("`package.{key}` must be a string"),
+                Self::span_of(package, key),
+            );
+        }
+        None
+    }
+
+    fn lint_package(&mut self, package: &dyn TableLike, package_span: Option<Range<usize>>) {
+        if package.get("name").and_then(Item::as_str).is_none() {
+            self.report(
+                "missing-name",
+                Severity::Error,
+                "`package.name` is required".to_string(),
+                package_span.clone(),
+            );
+        }
+
+        if let Some(version) = self.package_str(package, "version") {
+            if let Err(e) = semver::Version::parse(version) {
+                self.report(
+                    "invalid-version",
+                    Severity::Error,
+                    format!("`{version}` is not a valid semver version: {e}"),
+                    Self::span_of(package, "version"),
+                );
+            }
+        }
+
+        if let Some(edition) = self.package_str(package, "edition") {
+            if !EDITIONS.contains(&edition) {
+                self.report(
+                    "unknown-edition",
+                    Severity::Error,
+                    format!(
+                        "Unknown edition `{edition}`, expected one of {}",
+                        EDITIONS.join(", ")
+                    ),
+                    Self::span_of(package, "edition"),
+                );
+            }
+        }
+
+        if let Some(rust_version) = self.package_str(package, "rust-version") {
+            if parse_rust_version(rust_version).is_none() {
+                self.report(
+                    "invalid-rust-version",
+                    Severity::Error,
+                    format!(
+                        "`{rust_version}` is not a valid rust-version, expected e.g. `1.75` or `1.75.0`"
+                    ),
+                    Self::span_of(package, "rust-version"),
+                );
+            }
+        }
+
+        if !package.contains_key("license") && !package.contains_key("license-file") {
+            self.report(
+                "missing-license",
+                Severity::Warning,
+                "Neither `license` nor `license-file` is set".to_string(),
+                package_span,
+            );
+        }
+    }
+
+    /// Every dependency table in the manifest, including target-specific
+    /// ones, as `(display name, kind, table)`.
+    fn dependency_tables(root: &dyn TableLike) -> Vec<(String, &'static str, &dyn TableLike)> {
+        let mut tables = vec![];
+        for &kind in DEPENDENCY_TABLES {
+            if let Some(table) = root.get(kind).and_then(Item::as_table_like) {
+                tables.push((kind.to_string(), kind, table));
+            }
+        }
+        if let Some(targets) = root.get("target").and_then(Item::as_table_like) {
+            for (cfg, target) in targets.iter() {
+                let Some(target) = target.as_table_like() else {
+                    continue;
+                };
+                for &kind in DEPENDENCY_TABLES {
+                    if let Some(table) = target.get(kind).and_then(Item::as_table_like) {
+                        tables.push((format!("target.'{cfg}'.{kind}"), kind, table));
+                    }
+                }
+            }
+        }
+        tables
+    }
+
+    fn lint_dependencies(&mut self, root: &dyn TableLike) {
+        let tables = Self::dependency_tables(root);
+
+        let mut first_seen: HashMap<&str, &str> = HashMap::new();
+        for (name, _, table) in &tables {
+            for (dependency, _) in table.iter() {
+                match first_seen.get(dependency) {
+                    Some(first) => self.report(
+                        "duplicate-dependency",
+                        Severity::Warning,
+                        format!("`{dependency}` is declared in both [{first}] and [{name}]"),
+                        table.key(dependency).and_then(|key| key.span()),
+                    ),
+                    None => {
+                        first_seen.insert(dependency, name);
+                    }
+                }
+            }
+        }
+
+        // Features may only refer to normal and build dependencies.
+        let mut dependencies: BTreeMap<String, bool> = BTreeMap::new();
+        for (_, kind, table) in &tables {
+            if *kind == "dev-dependencies" {
+                continue;
+            }
+            for (dependency, item) in table.iter() {
+                let optional = item
+                    .as_table_like()
+                    .and_then(|detail| detail.get("optional"))
+                    .and_then(Item::as_bool)
+                    .unwrap_or(false);
+                *dependencies.entry(dependency.to_string()).or_default() |= optional;
+            }
+        }
+        self.lint_features(root, dependencies);
+    }
+
+    fn lint_features(&mut self, root: &dyn TableLike, dependencies: BTreeMap<String, bool>) {
+        let Some(features) = root.get("features").and_then(Item::as_table_like) else {
+            return;
+        };
+        let strings = features
+            .iter()
+            .map(|(feature, values)| {
+                let references = values
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|value| Some(value.as_str()?.to_string()))
+                    .collect();
+                (feature.to_string(), references)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let scope = FeatureScope::new(&strings, dependencies);
+
+        for (feature, values) in features.iter() {
+            let Some(values) = values.as_array() else {
+                self.report(
+                    "invalid-feature",
+                    Severity::Error,
+                    format!("Feature `{feature}` must be an array of strings"),
+                    Self::span_of(features, feature),
+                );
+                continue;
+            };
+            for value in values.iter() {
+                let Some(reference) = value.as_str() else {
+                    self.report(
+                        "invalid-feature",
+                        Severity::Error,
+                        format!("Feature `{feature}` must only contain strings"),
+                        value.span(),
+                    );
+                    continue;
+                };
+                if let Some(problem) = scope.problem(reference) {
+                    self.report(
+                        "invalid-feature-reference",
+                        Severity::Error,
+                        format!("Feature `{feature}` refers to `{reference}`: {problem}"),
+                        value.span(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn lint(mut self) -> Vec<Issue> {
+        let document = match ImDocument::parse(self.source) {
+            Ok(document) => document,
+            Err(e) => {
+                self.report(
+                    "parse-error",
+                    Severity::Error,
+                    e.message().to_string(),
+                    e.span(),
+                );
+                return self.issues;
+            }
+        };
+        let root = document.as_table();
+
+        match root.get("package").or_else(|| root.get("project")) {
+            Some(package) => match package.as_table_like() {
+                Some(table) => self.lint_package(table, package.span()),
+                None => self.report(
+                    "invalid-type",
+                    Severity::Error,
+                    "[package] must be a table".to_string(),
+                    package.span(),
+                ),
+            },
+            None if !root.contains_key("workspace") => self.report(
+                "missing-package",
+                Severity::Error,
+                "Manifest has neither [package] nor [workspace]".to_string(),
+                None,
+            ),
+            None => (),
+        }
+        self.lint_dependencies(root);
+
+        self.issues
+    }
+}
+
+pub async fn lint(body: String) -> impl IntoResponse {
+    let issues = Linter::new(&body).lint();
+    let count = |severity: fn(&Severity) -> bool| {
+        issues
+            .iter()
+            .filter(|issue| severity(&issue.severity))
+            .count()
+    };
+    let report = serde_json::json!({
+        "errors": count(|severity| matches!(severity, Severity::Error)),
+        "warnings": count(|severity| matches!(severity, Severity::Warning)),
+        "issues": issues,
+    });
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        report.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Location = ((usize, usize), (usize, usize));
+
+    /// Code and `((line, column), (line, column))` span of every issue.
+    fn issues(source: &str) -> Vec<(&'static str, Option<Location>)> {
+        Linter::new(source)
+            .lint()
+            .into_iter()
+            .map(|issue| {
+                let span = issue.span.map(|Span { start, end }| {
+                    ((start.line, start.column), (end.line, end.column))
+                });
+                (issue.code, span)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn positions_count_characters_not_bytes() {
+        let linter = Linter::new("a = \"ü€\"\nb");
+        // `ü` takes two bytes and `€` three.
+        let columns = [0, 5, 7, 10, 12].map(|offset| {
+            let Position { line, column } = linter.position(offset);
+            (line, column)
+        });
+        assert_eq!(columns, [(1, 1), (1, 6), (1, 7), (1, 8), (2, 1)]);
+    }
+
+    #[test]
+    fn package_fields_are_reported_at_their_values() {
+        let manifest = r#"[package]
+name = "demo"
+version = "1.0"
+edition = "2020"
+rust-version = "one"
+"#;
+        assert_eq!(
+            issues(manifest),
+            [
+                ("invalid-version", Some(((3, 11), (3, 16)))),
+                ("unknown-edition", Some(((4, 11), (4, 17)))),
+                ("invalid-rust-version", Some(((5, 16), (5, 21)))),
+                ("missing-license", Some(((1, 1), (5, 21)))),
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_after_multi_byte_characters() {
+        let manifest = r#"package = { name = "ünïcödé", version = "x", license = "MIT" }"#;
+        assert_eq!(
+            issues(manifest),
+            [("invalid-version", Some(((1, 41), (1, 44))))]
+        );
+    }
+
+    #[test]
+    fn package_problems_without_a_value_to_point_at() {
+        assert_eq!(
+            issues("[package]\nlicense = \"MIT\"\n"),
+            [("missing-name", Some(((1, 1), (2, 16))))]
+        );
+        assert_eq!(
+            issues("[package]\nname = \"x\"\nlicense = \"MIT\"\nversion = 1\n"),
+            [("invalid-type", Some(((4, 11), (4, 12))))]
+        );
+        assert_eq!(
+            issues("package = 1\n"),
+            [("invalid-type", Some(((1, 11), (1, 12))))]
+        );
+        assert_eq!(issues("[dependencies]\n"), [("missing-package", None)]);
+        assert_eq!(
+            issues("[package\n"),
+            [("parse-error", Some(((1, 9), (2, 1))))]
+        );
+    }
+
+    #[test]
+    fn dependencies_and_features_are_reported_where_they_are_declared() {
+        let manifest = r#"[workspace]
+
+[dependencies]
+serde = "1"
+
+[dev-dependencies]
+serde = "1"
+
+[features]
+default = ["missing"]
+broken = "serde"
+mixed = [1]
+"#;
+        assert_eq!(
+            issues(manifest),
+            [
+                ("duplicate-dependency", Some(((7, 1), (7, 6)))),
+                ("invalid-feature-reference", Some(((10, 12), (10, 21)))),
+                ("invalid-feature", Some(((11, 10), (11, 17)))),
+                ("invalid-feature", Some(((12, 10), (12, 11)))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Named manifest acceptance policies, read at startup from the file named by
+//! the `MANIFEST_POLICIES_PATH` secret or environment variable, or else the
+//! embedded `policies.toml`.
+
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+use axum::http::StatusCode;
+use cargo_manifest::{Edition, MaybeInherited};
+use serde::Deserialize;
+use serde_json::Value;
+use spdx::{Expression, ParseMode};
+
+use super::AnyManifest;
+
+pub(super) const DEFAULT_POLICY: &str = "default";
+
+/// Secret or environment variable naming a policies file to use instead of
+/// the embedded one.
+pub const POLICIES_PATH: &str = "MANIFEST_POLICIES_PATH";
+
+const EMBEDDED_POLICIES: &str = include_str!("./policies.toml");
+
+static POLICIES: OnceLock<HashMap<String, Policy>> = OnceLock::new();
+
+/// Read the policies from `path`, or the embedded file without one. Only the
+/// first successful call takes effect.
+pub fn load_policies(path: Option<&str>) -> Result<(), String> {
+    let policies = match path {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            toml::from_str(&text).map_err(|e| format!("{path}: {e}"))?
+        }
+        None => embedded_policies(),
+    };
+    let _ = POLICIES.set(policies);
+    Ok(())
+}
+
+fn embedded_policies() -> HashMap<String, Policy> {
+    toml::from_str(EMBEDDED_POLICIES).expect("Invalid embedded manifest policies")
+}
+
+type Rejection = (StatusCode, &'static str);
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Policy {
+    required_keywords: Vec<String>,
+    allowed_licenses: Option<Vec<String>>,
+    min_edition: Option<Edition>,
+    required_metadata_keys: Vec<String>,
+    max_dependencies: Option<usize>,
+}
+
+impl Policy {
+    pub(super) fn from_name(name: Option<&str>) -> Result<&'static Self, Rejection> {
+        POLICIES
+            .get_or_init(embedded_policies)
+            .get(name.unwrap_or(DEFAULT_POLICY))
+            .ok_or((StatusCode::BAD_REQUEST, "Unknown policy"))
+    }
+
+    /// Check `manifest` against every rule, returning its package metadata.
+    pub(super) fn validate(&self, manifest: AnyManifest) -> Result<Value, Rejection> {
+        let dependencies = dependency_count(&manifest);
+        let Some(package) = manifest.package else {
+            return Err((StatusCode::NO_CONTENT, "Empty package"));
+        };
+
+        let keywords = match package.keywords {
+            Some(MaybeInherited::Local(keywords)) => keywords,
+            _ => vec![],
+        };
+        if !self
+            .required_keywords
+            .iter()
+            .all(|keyword| keywords.contains(keyword))
+        {
+            return Err((StatusCode::BAD_REQUEST, "Magic keyword not provided"));
+        }
+
+        if let Some(allowed) = &self.allowed_licenses {
+            let license = package.license.and_then(MaybeInherited::as_local);
+            if !license.is_some_and(|license| license_allowed(&license, allowed)) {
+                return Err((StatusCode::BAD_REQUEST, "License not allowed"));
+            }
+        }
+
+        if let Some(min_edition) = self.min_edition {
+            let edition = match package.edition {
+                Some(MaybeInherited::Local(edition)) => edition,
+                // Without the workspace, the edition is not known.
+                Some(MaybeInherited::Inherited { .. }) => {
+                    return Err((StatusCode::BAD_REQUEST, "Edition is inherited"))
+                }
+                None => Edition::default(),
+            };
+            // Editions are years, so they compare correctly as strings.
+            if edition.as_str() < min_edition.as_str() {
+                return Err((StatusCode::BAD_REQUEST, "Edition too old"));
+            }
+        }
+
+        if self
+            .max_dependencies
+            .is_some_and(|max_dependencies| dependencies > max_dependencies)
+        {
+            return Err((StatusCode::BAD_REQUEST, "Too many dependencies"));
+        }
+
+        let Some(metadata) = package.metadata else {
+            return Err((StatusCode::NO_CONTENT, "Empty metadata"));
+        };
+        if !self
+            .required_metadata_keys
+            .iter()
+            .all(|key| metadata.get(key).is_some())
+        {
+            return Err((StatusCode::BAD_REQUEST, "Missing required metadata"));
+        }
+        Ok(metadata)
+    }
+}
+
+fn dependency_count(manifest: &AnyManifest) -> usize {
+    let tables = [
+        &manifest.dependencies,
+        &manifest.dev_dependencies,
+        &manifest.build_dependencies,
+    ];
+    let targets = manifest.target.iter().flatten().map(|(_, target)| {
+        target.dependencies.len() + target.dev_dependencies.len() + target.build_dependencies.len()
+    });
+    tables
+        .into_iter()
+        .flatten()
+        .map(|table| table.len())
+        .sum::<usize>()
+        + targets.sum::<usize>()
+}
+
+/// Whether an SPDX expression can be satisfied using only `allowed`
+/// licenses. Legacy `/` alternatives are accepted; anything that doesn't
+/// parse is not allowed.
+fn license_allowed(expression: &str, allowed: &[String]) -> bool {
+    let Ok(expression) = Expression::parse_mode(expression, ParseMode::LAX) else {
+        return false;
+    };
+    expression.evaluate(|license| allowed.contains(&license.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(expression: &str) -> bool {
+        license_allowed(expression, &["MIT".to_owned(), "Apache-2.0".to_owned()])
+    }
+
+    #[test]
+    fn license_expressions_follow_precedence() {
+        assert!(allowed("MIT"));
+        assert!(allowed("MIT OR Apache-2.0"));
+        assert!(allowed("MIT/Apache-2.0"));
+        assert!(allowed("MIT AND Apache-2.0"));
+        assert!(allowed("GPL-3.0-only OR MIT AND Apache-2.0"));
+        assert!(allowed("(GPL-3.0-only OR MIT) AND Apache-2.0"));
+        assert!(!allowed("(MIT OR Apache-2.0) AND GPL-3.0-only"));
+        assert!(!allowed("MIT AND GPL-3.0-only OR Zlib"));
+        assert!(!allowed("GPL-3.0-only"));
+    }
+
+    #[test]
+    fn unparseable_licenses_are_refused() {
+        assert!(!allowed(""));
+        assert!(!allowed("MIT OR"));
+        assert!(!allowed("(MIT"));
+        assert!(!allowed("Not-A-License"));
+    }
+
+    fn validate_edition(edition: &str) -> Result<Value, Rejection> {
+        let policy: Policy = toml::from_str(r#"min_edition = "2021""#).unwrap();
+        let manifest = format!("[package]\nname = \"x\"\n{edition}\nmetadata.orders = []\n");
+        policy.validate(toml::from_str(&manifest).unwrap())
+    }
+
+    #[test]
+    fn inherited_editions_are_told_apart_from_old_ones() {
+        assert!(validate_edition(r#"edition = "2021""#).is_ok());
+        assert_eq!(
+            validate_edition(r#"edition = "2018""#).err(),
+            Some((StatusCode::BAD_REQUEST, "Edition too old"))
+        );
+        assert_eq!(
+            validate_edition("edition.workspace = true").err(),
+            Some((StatusCode::BAD_REQUEST, "Edition is inherited"))
+        );
+    }
+
+    #[test]
+    fn embedded_policies_parse() {
+        let policies = embedded_policies();
+        assert!(policies.contains_key(DEFAULT_POLICY));
+        assert!(policies.contains_key("strict"));
+    }
+}
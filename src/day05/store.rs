@@ -0,0 +1,231 @@
+//! Persistence of manifest orders, and the `/5/orders` query endpoints.
+
+use axum::{
+    extract::{Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::{
+        chrono::{DateTime, NaiveDate, NaiveTime, Utc},
+        Uuid,
+    },
+    PgPool,
+};
+
+use super::{csv_field, OrderSummary};
+use crate::AppState;
+
+/// Record the package and every order of `summary`, returning the package ID.
+pub(super) async fn store_summary(pool: &PgPool, summary: &OrderSummary) -> sqlx::Result<Uuid> {
+    let package_id = Uuid::new_v4();
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO manifest_packages (id, name, version) VALUES ($1, $2, $3)",
+        package_id,
+        summary.package.name,
+        summary.package.version,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for order in &summary.orders {
+        let quantity = i64::try_from(order.quantity).map_err(|e| sqlx::Error::Encode(e.into()))?;
+        sqlx::query!(
+            "INSERT INTO manifest_orders (id, package_id, item, quantity, unit, price)
+                VALUES ($1, $2, $3, $4, $5, $6)",
+            Uuid::new_v4(),
+            package_id,
+            order.item,
+            quantity,
+            order.unit,
+            order.price,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(package_id)
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Filters shared by `/5/orders` and `/5/orders/totals`. Dates are inclusive
+/// and interpreted in UTC.
+#[derive(Deserialize)]
+pub struct OrderFilter {
+    item: Option<String>,
+    package: Option<String>,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+impl OrderFilter {
+    fn since(&self) -> Option<DateTime<Utc>> {
+        Some(self.from?.and_time(NaiveTime::MIN).and_utc())
+    }
+
+    fn until(&self) -> Option<DateTime<Utc>> {
+        Some(self.to?.succ_opt()?.and_time(NaiveTime::MIN).and_utc())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StoredOrder {
+    id: Uuid,
+    package: String,
+    version: Option<String>,
+    item: String,
+    quantity: i64,
+    unit: Option<String>,
+    price: Option<f64>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct ItemTotal {
+    item: String,
+    unit: Option<String>,
+    quantity: i64,
+    orders: i64,
+    price: Option<f64>,
+}
+
+/// Render rows as JSON or as CSV with the given header.
+fn export<T: Serialize>(
+    format: ExportFormat,
+    rows: &[T],
+    header: &str,
+    to_fields: impl Fn(&T) -> Vec<String>,
+) -> Response {
+    match format {
+        ExportFormat::Json => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, "application/json")],
+            serde_json::to_string(rows).unwrap(),
+        )
+            .into_response(),
+        ExportFormat::Csv => {
+            let mut csv = format!("{header}\n");
+            for row in rows {
+                csv.push_str(&to_fields(row).join(","));
+                csv.push('\n');
+            }
+            (
+                StatusCode::OK,
+                [(CONTENT_TYPE, "text/csv; charset=utf-8")],
+                csv,
+            )
+                .into_response()
+        }
+    }
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(T::to_string).unwrap_or_default()
+}
+
+pub async fn orders(State(state): State<AppState>, Query(filter): Query<OrderFilter>) -> Response {
+    let pool = &state.read().await.pool;
+    // The filter is repeated in `order_totals`.
+    let Ok(orders) = sqlx::query_as!(
+        StoredOrder,
+        "SELECT o.id, p.name AS package, p.version, o.item, o.quantity, o.unit, o.price, o.created_at
+            FROM manifest_orders o
+            JOIN manifest_packages p ON p.id = o.package_id
+            WHERE ($1::TEXT IS NULL OR o.item = $1)
+                AND ($2::TEXT IS NULL OR p.name = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR o.created_at >= $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR o.created_at < $4)
+            ORDER BY o.created_at, o.id",
+        filter.item,
+        filter.package,
+        filter.since(),
+        filter.until(),
+    )
+    .fetch_all(pool)
+    .await
+    else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed".to_string(),
+        )
+            .into_response();
+    };
+
+    export(
+        filter.format,
+        &orders,
+        "id,package,version,item,quantity,unit,price,created_at",
+        |order| {
+            vec![
+                order.id.to_string(),
+                csv_field(&order.package),
+                csv_field(&optional(&order.version)),
+                csv_field(&order.item),
+                order.quantity.to_string(),
+                csv_field(&optional(&order.unit)),
+                optional(&order.price),
+                order.created_at.to_rfc3339(),
+            ]
+        },
+    )
+}
+
+pub async fn order_totals(
+    State(state): State<AppState>,
+    Query(filter): Query<OrderFilter>,
+) -> Response {
+    let pool = &state.read().await.pool;
+    let Ok(totals) = sqlx::query_as!(
+        ItemTotal,
+        r#"SELECT o.item, o.unit,
+                SUM(o.quantity)::BIGINT AS "quantity!",
+                COUNT(*) AS "orders!",
+                SUM(o.quantity * o.price) AS price
+            FROM manifest_orders o
+            JOIN manifest_packages p ON p.id = o.package_id
+            WHERE ($1::TEXT IS NULL OR o.item = $1)
+                AND ($2::TEXT IS NULL OR p.name = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR o.created_at >= $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR o.created_at < $4)
+            GROUP BY o.item, o.unit
+            ORDER BY o.item, o.unit"#,
+        filter.item,
+        filter.package,
+        filter.since(),
+        filter.until(),
+    )
+    .fetch_all(pool)
+    .await
+    else {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed".to_string(),
+        )
+            .into_response();
+    };
+
+    export(
+        filter.format,
+        &totals,
+        "item,unit,quantity,orders,price",
+        |total| {
+            vec![
+                csv_field(&total.item),
+                csv_field(&optional(&total.unit)),
+                total.quantity.to_string(),
+                total.orders.to_string(),
+                optional(&total.price),
+            ]
+        },
+    )
+}
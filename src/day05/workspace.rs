@@ -0,0 +1,260 @@
+//! Multipart uploads of a workspace root manifest together with its members.
+
+use axum::{
+    extract::Multipart,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use cargo_manifest::{MaybeInherited, Workspace};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+
+use super::{AnyManifest, ManifestError, ManifestFormat, ManifestParams, OrderError, OrderSummary};
+
+/// Name of the multipart field holding the workspace root manifest. Every
+/// other field is treated as a member manifest.
+const WORKSPACE_FIELD: &str = "workspace";
+
+/// Replace every `field.workspace = true` in `[package]` with the value from
+/// `[workspace.package]`, and merge `[workspace.metadata]` underneath the
+/// member's own `[package.metadata]`.
+fn inherit(manifest: &mut AnyManifest, workspace: &Workspace<Value>) -> Result<(), &'static str> {
+    let Some(package) = manifest.package.as_mut() else {
+        return Ok(());
+    };
+    let inherited = workspace.package.clone().unwrap_or_default();
+
+    macro_rules! inherit_fields {
+        ($($field:ident => $key:literal),* $(,)?) => {$(
+            if let Some(MaybeInherited::Inherited { .. }) = package.$field {
+                let value = inherited.$field.clone().ok_or(concat!(
+                    "`",
+                    $key,
+                    "` is inherited but not set in [workspace.package]"
+                ))?;
+                package.$field = Some(MaybeInherited::Local(value));
+            }
+        )*};
+    }
+    inherit_fields!(
+        edition => "edition",
+        version => "version",
+        authors => "authors",
+        description => "description",
+        homepage => "homepage",
+        documentation => "documentation",
+        readme => "readme",
+        keywords => "keywords",
+        categories => "categories",
+        license => "license",
+        license_file => "license-file",
+        repository => "repository",
+        rust_version => "rust-version",
+        exclude => "exclude",
+        include => "include",
+        publish => "publish",
+    );
+
+    match (&mut package.metadata, &workspace.metadata) {
+        (Some(Value::Object(metadata)), Some(Value::Object(workspace_metadata))) => {
+            for (key, value) in workspace_metadata {
+                metadata.entry(key).or_insert_with(|| value.clone());
+            }
+        }
+        (metadata @ None, Some(workspace_metadata)) => *metadata = Some(workspace_metadata.clone()),
+        _ => (),
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum MemberOutcome {
+    Summary(OrderSummary),
+    Rejected { error: &'static str },
+    InvalidOrders { errors: Vec<OrderError> },
+}
+
+/// The outcome of processing one manifest out of many, with the status code
+/// it would have been answered with on its own.
+#[derive(Serialize)]
+pub(super) struct Report {
+    status: u16,
+    #[serde(flatten)]
+    outcome: MemberOutcome,
+}
+
+impl Report {
+    pub(super) fn new(result: Result<OrderSummary, ManifestError>) -> Self {
+        let (status, outcome) = match result {
+            Ok(summary) => (StatusCode::OK, MemberOutcome::Summary(summary)),
+            Err(ManifestError::Rejected(status_code, error)) => {
+                (status_code, MemberOutcome::Rejected { error })
+            }
+            Err(ManifestError::InvalidOrders(errors)) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                MemberOutcome::InvalidOrders { errors },
+            ),
+        };
+        Self {
+            status: status.as_u16(),
+            outcome,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MemberReport {
+    name: String,
+    #[serde(flatten)]
+    report: Report,
+}
+
+/// Validate each member manifest against the workspace root uploaded in the
+/// `workspace` field, reporting a result per member.
+pub(super) async fn process_workspace(
+    mut multipart: Multipart,
+    params: &ManifestParams,
+    pool: Option<&PgPool>,
+) -> Response {
+    let mut root = None;
+    let mut members = vec![];
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response(),
+        };
+        let format = ManifestFormat::from_part(field.content_type(), field.file_name());
+        let is_root = field.name() == Some(WORKSPACE_FIELD);
+        let file_name = field.file_name().map(str::to_string);
+        let Ok(text) = field.text().await else {
+            return (StatusCode::BAD_REQUEST, "Invalid multipart body").into_response();
+        };
+
+        let manifest = format.and_then(|format| format.parse_manifest(&text));
+        if is_root {
+            let Some(manifest) = manifest else {
+                return (StatusCode::BAD_REQUEST, "Invalid workspace manifest").into_response();
+            };
+            root = Some(manifest);
+        } else {
+            members.push((file_name, manifest));
+        }
+    }
+
+    let Some(workspace) = root.and_then(|root| root.workspace) else {
+        return (StatusCode::BAD_REQUEST, "Missing [workspace] manifest").into_response();
+    };
+
+    let results = members
+        .into_iter()
+        .enumerate()
+        .map(|(index, (file_name, manifest))| {
+            let name = file_name
+                .or_else(|| manifest.as_ref()?.package.as_ref().map(|p| p.name.clone()))
+                .unwrap_or_else(|| format!("#{index}"));
+            let result = manifest
+                .ok_or(ManifestError::Rejected(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid manifest",
+                ))
+                .and_then(|mut manifest| {
+                    inherit(&mut manifest, &workspace)
+                        .map_err(|e| ManifestError::Rejected(StatusCode::BAD_REQUEST, e))?;
+                    super::summarize(manifest, params)
+                });
+            (name, result)
+        })
+        .collect::<Vec<_>>();
+
+    let mut reports = Vec::with_capacity(results.len());
+    for (name, mut result) in results {
+        if let (Some(pool), Ok(summary)) = (pool, &result) {
+            if super::store::store_summary(pool, summary).await.is_err() {
+                result = Err(ManifestError::Rejected(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to store orders",
+                ));
+            }
+        }
+        reports.push(MemberReport {
+            name,
+            report: Report::new(result),
+        });
+    }
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        serde_json::json!({ "members": reports }).to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inherit_from(member: &str, root: &str) -> Result<AnyManifest, &'static str> {
+        let mut manifest: AnyManifest = toml::from_str(member).unwrap();
+        let root: AnyManifest = toml::from_str(root).unwrap();
+        inherit(&mut manifest, &root.workspace.unwrap()).map(|()| manifest)
+    }
+
+    const MEMBER: &str = r#"
+        [package]
+        name = "member"
+        rust-version.workspace = true
+    "#;
+
+    #[test]
+    fn inherited_fields_take_the_workspace_value() {
+        let root = r#"
+            [workspace.package]
+            rust-version = "1.80"
+        "#;
+        let manifest = inherit_from(MEMBER, root).unwrap();
+        let package = manifest.package.unwrap();
+        assert!(matches!(
+            package.rust_version,
+            Some(MaybeInherited::Local(version)) if version == "1.80"
+        ));
+    }
+
+    #[test]
+    fn missing_workspace_values_are_named_by_their_manifest_key() {
+        let root = r#"
+            [workspace.package]
+            edition = "2021"
+        "#;
+        assert_eq!(
+            inherit_from(MEMBER, root).err(),
+            Some("`rust-version` is inherited but not set in [workspace.package]")
+        );
+    }
+
+    #[test]
+    fn member_metadata_wins_over_the_workspace() {
+        let member = r#"
+            [package]
+            name = "member"
+
+            [package.metadata]
+            colour = "red"
+        "#;
+        let root = r#"
+            [workspace]
+
+            [workspace.metadata]
+            colour = "green"
+            size = "large"
+        "#;
+        let manifest = inherit_from(member, root).unwrap();
+        assert_eq!(
+            manifest.package.unwrap().metadata,
+            Some(serde_json::json!({ "colour": "red", "size": "large" }))
+        );
+    }
+}
@@ -1,57 +1,425 @@
+mod ledger;
+mod volume;
+
+pub use ledger::{ledger, stock};
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
 use axum::{
-    extract::{rejection::JsonRejection, State},
+    extract::{rejection::JsonRejection, ConnectInfo, Query, State},
     http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::{
+        chrono::{DateTime, Utc},
+        Json as JsonColumn, Uuid,
+    },
+    PgPool,
+};
 
-use crate::AppState;
+use crate::{
+    auth::bearer_matches,
+    rate_limit::{Algorithm, Client, KeyedLimiter, Policy, SharedLimiter},
+    AppState,
+};
+use ledger::EntryKind;
+use volume::{BucketUnit, MilkRequest, Rounding, VolumeUnit};
+
+/// What a plain request hands out, and what each glass a refill restores
+/// puts back in stock.
+const GLASS: BucketUnit = BucketUnit {
+    unit: VolumeUnit::Cups,
+    amount: 1.0,
+};
+/// Bearer token required to change the milk bucket.
+const ADMIN_TOKEN: &str = "MILK_ADMIN_TOKEN";
+/// Most glasses the bucket can be configured to hold.
+const MAX_CAPACITY: usize = 1_000_000;
+/// Longest the bucket can be configured to wait between refills.
+const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
-pub enum BucketUnit {
-    Liters(f32),
-    Gallons(f32),
-    Litres(f32),
-    Pints(f32),
+#[derive(Deserialize)]
+pub struct MilkParams {
+    /// Unit to convert to, instead of the given unit's usual counterpart.
+    to: Option<VolumeUnit>,
+    /// Round the result to this many decimal places.
+    decimals: Option<u32>,
+    #[serde(default)]
+    rounding: Rounding,
 }
 
-impl BucketUnit {
-    fn convert(self) -> Self {
-        const LITERS_TO_GALLONS: f32 = 3.7854125;
-        const LITRES_TO_PINTS: f32 = 1.759754;
-        match self {
-            Self::Liters(amount) => Self::Gallons(amount / LITERS_TO_GALLONS),
-            Self::Gallons(amount) => Self::Liters(amount * LITERS_TO_GALLONS),
-            Self::Pints(amount) => Self::Litres(amount / LITRES_TO_PINTS),
-            Self::Litres(amount) => Self::Pints(amount * LITRES_TO_PINTS),
+impl MilkParams {
+    fn convert(&self, bucket: BucketUnit) -> Result<BucketUnit, String> {
+        let mut converted = bucket.convert(self.to)?;
+        if let Some(decimals) = self.decimals {
+            converted.amount = self.rounding.round(converted.amount, decimals)?;
         }
+        Ok(converted)
+    }
+}
+
+/// Santa's milk bucket: five glasses, one more every second. Converting a
+/// batch takes a glass per bucket in it.
+pub const MILK_POLICY: Policy = Policy {
+    name: "milk",
+    algorithm: Algorithm::TokenBucket {
+        capacity: 5,
+        refill: 1,
+        interval: Duration::from_secs(1),
+    },
+    rejection: "No milk available\n",
+    cost: Some(milk_cost),
+};
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers.contains_key(CONTENT_TYPE) && headers[CONTENT_TYPE] == "application/json"
+}
+
+/// One glass per bucket to convert, and one for anything else, including
+/// requests that will be rejected as malformed.
+fn milk_cost(headers: &HeaderMap, body: &[u8]) -> usize {
+    if !is_json(headers) {
+        return 1;
     }
+    MilkRequest::from_slice(body).map_or(1, |request| request.buckets.len())
 }
 
+/// Rate limited per client by [`MILK_POLICY`]; a batch is converted whole or
+/// rejected whole. Milk handed out is withdrawn from the ledger, each bucket
+/// as asked for or a glass for a plain request, and the glasses are given
+/// back to the client if that fails.
 pub async fn milk(
     State(state): State<AppState>,
+    client: Option<Extension<Client>>,
+    Query(params): Query<MilkParams>,
     headers: HeaderMap,
-    payload: Result<Json<BucketUnit>, JsonRejection>,
+    payload: Result<Json<MilkRequest>, JsonRejection>,
 ) -> impl IntoResponse {
-    if !state.read().await.rate_limiter.try_acquire(1) {
-        return (StatusCode::TOO_MANY_REQUESTS, "No milk available\n").into_response();
+    let client = client.map_or(Client::Unknown, |Extension(client)| client);
+    let (withdrawn, body) = match is_json(&headers) {
+        true => match convert_buckets(&params, payload) {
+            Ok(converted) => converted,
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        },
+        false => (vec![GLASS], "Milk withdrawn\n".to_string()),
+    };
+    let (milk_limiter, pool) = {
+        let state = state.read().await;
+        (state.milk_limiter.clone(), state.pool.clone())
+    };
+    if ledger::record(
+        &pool,
+        EntryKind::Withdrawal,
+        &client.to_string(),
+        &withdrawn,
+    )
+    .await
+    .is_err()
+    {
+        // Best effort: the request failed either way.
+        let _ = milk_limiter.refund(&client, withdrawn.len()).await;
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to record withdrawal",
+        )
+            .into_response();
     }
-    if !headers.contains_key(CONTENT_TYPE) || headers[CONTENT_TYPE] != "application/json" {
-        return (StatusCode::OK, "Milk withdrawn\n").into_response();
+    (StatusCode::OK, body).into_response()
+}
+
+/// The buckets asked for, and the response body with each converted.
+fn convert_buckets(
+    params: &MilkParams,
+    payload: Result<Json<MilkRequest>, JsonRejection>,
+) -> Result<(Vec<BucketUnit>, String), String> {
+    let Json(request) = payload.map_err(|rejection| rejection.body_text())?;
+    let converted = request
+        .buckets
+        .iter()
+        .map(|&bucket| params.convert(bucket))
+        .collect::<Result<Vec<_>, _>>()?;
+    let body = match request.single {
+        true => serde_json::to_string(&converted[0]),
+        false => serde_json::to_string(&converted),
+    };
+    Ok((request.buckets, body.unwrap()))
+}
+
+/// Picks a client's bucket by address or API key.
+#[derive(Deserialize)]
+pub struct ClientParams {
+    ip: Option<IpAddr>,
+    key: Option<String>,
+}
+
+impl ClientParams {
+    fn client(self, limiter: &KeyedLimiter) -> Result<Option<Client>, StatusCode> {
+        match (self.ip, self.key) {
+            (Some(_), Some(_)) => Err(StatusCode::BAD_REQUEST),
+            (Some(ip), None) => Ok(Some(Client::Ip(ip))),
+            (None, Some(key)) => Ok(Some(limiter.api_key(&key))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Refills the chosen client's bucket, or every bucket if none is chosen.
+/// The glasses this restores are deposited in the ledger as milk.
+pub async fn refill(
+    State(state): State<AppState>,
+    Query(params): Query<ClientParams>,
+) -> impl IntoResponse {
+    let (milk_limiter, pool) = {
+        let state = state.read().await;
+        (state.milk_limiter.clone(), state.pool.clone())
+    };
+    let client = match params.client(&milk_limiter) {
+        Ok(client) => client,
+        Err(status_code) => return status_code,
+    };
+    let Ok(restored) = milk_limiter.reset(client.as_ref()).await else {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    };
+    if restored == 0 {
+        return StatusCode::OK;
+    }
+    let depositor = client.map_or("all".to_string(), |client| client.to_string());
+    let deposit = [BucketUnit {
+        amount: restored as f64,
+        ..GLASS
+    }];
+    match ledger::record(&pool, EntryKind::Deposit, &depositor, &deposit).await {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The milk bucket's limits, shared by every client.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct BucketConfig {
+    capacity: usize,
+    /// Milk added per interval.
+    refill: usize,
+    /// Seconds between refills.
+    interval: f64,
+}
+
+impl BucketConfig {
+    fn from_algorithm(algorithm: Algorithm) -> Option<Self> {
+        match algorithm {
+            Algorithm::TokenBucket {
+                capacity,
+                refill,
+                interval,
+            } => Some(Self {
+                capacity,
+                refill,
+                interval: interval.as_secs_f64(),
+            }),
+            Algorithm::SlidingWindow { .. } => None,
+        }
     }
-    match payload {
-        Ok(Json(bucket_unit)) => (
-            StatusCode::OK,
-            serde_json::to_string(&bucket_unit.convert()).unwrap(),
+
+    fn to_algorithm(self) -> Result<Algorithm, String> {
+        if !(1..=MAX_CAPACITY).contains(&self.capacity) {
+            return Err(format!("Capacity must be between 1 and {MAX_CAPACITY}"));
+        }
+        if !(1..=self.capacity).contains(&self.refill) {
+            return Err("Refill must be between 1 and the capacity".to_string());
+        }
+        match Duration::try_from_secs_f64(self.interval) {
+            Ok(interval) if !interval.is_zero() && interval <= MAX_INTERVAL => {
+                Ok(Algorithm::TokenBucket {
+                    capacity: self.capacity,
+                    refill: self.refill,
+                    interval,
+                })
+            }
+            _ => Err(format!(
+                "Interval must be a positive number of seconds, at most {}",
+                MAX_INTERVAL.as_secs()
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BucketReport {
+    #[serde(flatten)]
+    config: BucketConfig,
+    client: String,
+    level: usize,
+}
+
+/// Reports the bucket's limits and the chosen client's level, the calling
+/// client's if none is chosen.
+pub async fn bucket(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<ClientParams>,
+) -> Response {
+    let milk_limiter = state.read().await.milk_limiter.clone();
+    let client = match params.client(&milk_limiter) {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+            let Ok(client) = milk_limiter.identify(peer, &headers) else {
+                return (StatusCode::UNAUTHORIZED, "Invalid API key\n").into_response();
+            };
+            client
+        }
+        Err(status_code) => return status_code.into_response(),
+    };
+    let algorithm = milk_limiter.algorithm().await;
+    let Some(config) = BucketConfig::from_algorithm(algorithm) else {
+        return (StatusCode::CONFLICT, "Milk is not a bucket\n").into_response();
+    };
+    let Ok(status) = milk_limiter.peek(&client).await else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    Json(BucketReport {
+        config,
+        client: client.to_string(),
+        level: status.remaining,
+    })
+    .into_response()
+}
+
+/// Check the admin token, handing out what admin endpoints work on.
+async fn authorize_admin(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<(SharedLimiter, PgPool), Response> {
+    let state = state.read().await;
+    let Some(token) = state.secrets.get(ADMIN_TOKEN) else {
+        return Err((
+            StatusCode::FAILED_DEPENDENCY,
+            "Failed to load secrets".to_string(),
+        )
+            .into_response());
+    };
+    if !bearer_matches(headers, &token) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid token".to_string()).into_response());
+    }
+    Ok((state.milk_limiter.clone(), state.pool.clone()))
+}
+
+/// Fields left out keep their current value.
+#[derive(Deserialize)]
+pub struct BucketUpdate {
+    capacity: Option<usize>,
+    refill: Option<usize>,
+    interval: Option<f64>,
+}
+
+/// Changes the bucket's limits for every client and every replica, keeping
+/// their levels, and records the change in the audit log.
+pub async fn update_bucket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(update): Json<BucketUpdate>,
+) -> Response {
+    let (milk_limiter, pool) = match authorize_admin(&state, &headers).await {
+        Ok(authorized) => authorized,
+        Err(response) => return response,
+    };
+
+    let algorithm = milk_limiter.algorithm().await;
+    let Some(previous) = BucketConfig::from_algorithm(algorithm) else {
+        return (StatusCode::CONFLICT, "Milk is not a bucket\n").into_response();
+    };
+    let current = BucketConfig {
+        capacity: update.capacity.unwrap_or(previous.capacity),
+        refill: update.refill.unwrap_or(previous.refill),
+        interval: update.interval.unwrap_or(previous.interval),
+    };
+    let algorithm = match current.to_algorithm() {
+        Ok(algorithm) => algorithm,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    };
+
+    // The change and its audit entry are committed together, so nothing goes
+    // unaudited.
+    let recorded = async {
+        let mut transaction = pool.begin().await?;
+        sqlx::query!(
+            "INSERT INTO bucket_audit_log (id, previous, current) VALUES ($1, $2, $3)",
+            Uuid::new_v4(),
+            JsonColumn(previous) as _,
+            JsonColumn(current) as _,
         )
-            .into_response(),
-        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        .execute(&mut *transaction)
+        .await?;
+        milk_limiter.set_algorithm(transaction, algorithm).await
+    };
+    if recorded.await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to record change").into_response();
     }
+    Json(current).into_response()
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    id: Uuid,
+    changed_at: DateTime<Utc>,
+    previous: JsonColumn<BucketConfig>,
+    current: JsonColumn<BucketConfig>,
 }
 
-pub async fn refill(State(state): State<AppState>) -> impl IntoResponse {
-    state.write().await.reload_rate_limiter();
-    StatusCode::OK
+/// Lists every change made to the bucket, most recent first.
+pub async fn bucket_audit_log(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let (_, pool) = match authorize_admin(&state, &headers).await {
+        Ok(authorized) => authorized,
+        Err(response) => return response,
+    };
+    match sqlx::query_as!(
+        AuditEntry,
+        r#"SELECT id, changed_at,
+                previous AS "previous: JsonColumn<BucketConfig>",
+                current AS "current: JsonColumn<BucketConfig>"
+            FROM bucket_audit_log
+            ORDER BY changed_at DESC"#,
+    )
+    .fetch_all(&pool)
+    .await
+    {
+        Ok(entries) => Json(entries).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Query failed").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(capacity: usize, refill: usize, interval: f64) -> BucketConfig {
+        BucketConfig {
+            capacity,
+            refill,
+            interval,
+        }
+    }
+
+    #[test]
+    fn bucket_configs_are_bounded() {
+        assert!(config(5, 1, 1.0).to_algorithm().is_ok());
+        assert!(config(MAX_CAPACITY, MAX_CAPACITY, 86400.0)
+            .to_algorithm()
+            .is_ok());
+        assert!(config(0, 1, 1.0).to_algorithm().is_err());
+        assert!(config(MAX_CAPACITY + 1, 1, 1.0).to_algorithm().is_err());
+        assert!(config(5, 0, 1.0).to_algorithm().is_err());
+        assert!(config(5, 6, 1.0).to_algorithm().is_err());
+        assert!(config(5, 1, 0.0).to_algorithm().is_err());
+        assert!(config(5, 1, 86400.5).to_algorithm().is_err());
+        assert!(config(5, 1, f64::NAN).to_algorithm().is_err());
+        assert!(config(5, 1, f64::INFINITY).to_algorithm().is_err());
+    }
 }
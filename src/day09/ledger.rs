@@ -0,0 +1,353 @@
+//! The milk inventory: every withdrawal and deposit, and the stock they add
+//! up to.
+//!
+//! Stock is kept as volume: each entry records the milk as handed out or
+//! poured in, and the millilitres that adds or takes away. Santa's supply
+//! tops the stock up with whatever a withdrawal needs beyond what is left, so
+//! it never goes below zero; these top-ups are deposits by [`SUPPLY`].
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+
+use super::volume::{BucketUnit, VolumeUnit};
+use crate::AppState;
+
+/// Unit balances are reported in unless another is asked for.
+const DEFAULT_UNIT: VolumeUnit = VolumeUnit::Liters;
+/// Depositor of the automatic top-ups.
+const SUPPLY: &str = "supply";
+
+#[derive(Clone, Copy)]
+pub(super) enum EntryKind {
+    Withdrawal,
+    Deposit,
+}
+
+impl EntryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Withdrawal => "withdrawal",
+            Self::Deposit => "deposit",
+        }
+    }
+
+    /// What moving `milk` does to the stock, in millilitres.
+    fn change(self, milk: BucketUnit) -> f64 {
+        match self {
+            Self::Withdrawal => -milk.millilitres(),
+            Self::Deposit => milk.millilitres(),
+        }
+    }
+}
+
+/// The top-up needed before withdrawing `milk` from `stock` millilitres, if
+/// any.
+fn top_up(stock: f64, milk: &[BucketUnit]) -> Option<BucketUnit> {
+    let needed = milk.iter().map(|milk| milk.millilitres()).sum::<f64>();
+    let shortfall = needed - stock.max(0.0);
+    (shortfall > 0.0).then_some(BucketUnit {
+        unit: VolumeUnit::Millilitres,
+        amount: shortfall,
+    })
+}
+
+async fn insert(
+    transaction: &mut sqlx::PgConnection,
+    kind: EntryKind,
+    client: &str,
+    milk: BucketUnit,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "INSERT INTO milk_ledger (kind, client, amount, unit, millilitres)
+            VALUES ($1, $2, $3, $4, $5)",
+        kind.as_str(),
+        client,
+        milk.amount,
+        milk.unit.to_string(),
+        kind.change(milk),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Record `milk` as taken from or added to the stock, all or none of it. A
+/// withdrawal the stock cannot cover is topped up first.
+pub(super) async fn record(
+    pool: &PgPool,
+    kind: EntryKind,
+    client: &str,
+    milk: &[BucketUnit],
+) -> sqlx::Result<()> {
+    let mut transaction = pool.begin().await?;
+    if let EntryKind::Withdrawal = kind {
+        // One withdrawal at a time, so each top-up sees the ones before.
+        sqlx::query!("LOCK TABLE milk_ledger IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *transaction)
+            .await?;
+        let stock = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(millilitres), 0) AS "stock!" FROM milk_ledger"#
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if let Some(top_up) = top_up(stock, milk) {
+            insert(&mut transaction, EntryKind::Deposit, SUPPLY, top_up).await?;
+        }
+    }
+    for &milk in milk {
+        insert(&mut transaction, kind, client, milk).await?;
+    }
+    transaction.commit().await
+}
+
+/// `millilitres` as an amount of `unit`.
+fn volume(millilitres: f64, unit: VolumeUnit) -> f64 {
+    millilitres / unit.millilitres()
+}
+
+/// Filters for `/9/ledger`. Balances always count every earlier entry, not
+/// just the ones shown.
+#[derive(Deserialize)]
+pub struct LedgerParams {
+    client: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    /// Unit to report balances in.
+    unit: Option<VolumeUnit>,
+}
+
+#[derive(Serialize)]
+struct LedgerEntry {
+    id: i64,
+    recorded_at: DateTime<Utc>,
+    kind: String,
+    client: String,
+    amount: f64,
+    unit: String,
+    /// Stock right after this entry, in millilitres until converted.
+    balance: f64,
+}
+
+/// The entries `params` picks out, oldest first, with balances in
+/// millilitres.
+async fn entries(pool: &PgPool, params: &LedgerParams) -> sqlx::Result<Vec<LedgerEntry>> {
+    sqlx::query_as!(
+        LedgerEntry,
+        r#"SELECT id AS "id!", recorded_at AS "recorded_at!", kind AS "kind!",
+                client AS "client!", amount AS "amount!", unit AS "unit!",
+                balance AS "balance!"
+            FROM (
+                SELECT *, SUM(millilitres) OVER (ORDER BY recorded_at, id) AS balance
+                    FROM milk_ledger
+            ) l
+            WHERE ($1::TEXT IS NULL OR client = $1)
+                AND ($2::TIMESTAMPTZ IS NULL OR recorded_at >= $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR recorded_at < $3)
+            ORDER BY recorded_at, id"#,
+        params.client,
+        params.from,
+        params.to,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Lists ledger entries oldest first, each with the running balance.
+/// Requires the admin token, as entries name clients.
+pub async fn ledger(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<LedgerParams>,
+) -> Response {
+    let pool = match super::authorize_admin(&state, &headers).await {
+        Ok((_, pool)) => pool,
+        Err(response) => return response,
+    };
+    let Ok(mut entries) = entries(&pool, &params).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Query failed").into_response();
+    };
+
+    let unit = params.unit.unwrap_or(DEFAULT_UNIT);
+    for entry in &mut entries {
+        entry.balance = volume(entry.balance, unit);
+    }
+    Json(serde_json::json!({ "unit": unit, "entries": entries })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct StockParams {
+    /// Report the stock as it was at this time, instead of now.
+    at: Option<DateTime<Utc>>,
+    unit: Option<VolumeUnit>,
+}
+
+#[derive(Serialize)]
+struct Stock {
+    amount: f64,
+    unit: VolumeUnit,
+    at: DateTime<Utc>,
+}
+
+/// Millilitres in stock at `at`: everything deposited less everything
+/// withdrawn by then.
+async fn stock_at(pool: &PgPool, at: DateTime<Utc>) -> sqlx::Result<f64> {
+    sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(millilitres), 0) AS "millilitres!"
+            FROM milk_ledger WHERE recorded_at <= $1"#,
+        at,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Reports the stock, in litres unless another unit is asked for.
+pub async fn stock(State(state): State<AppState>, Query(params): Query<StockParams>) -> Response {
+    let pool = state.read().await.pool.clone();
+    let at = params.at.unwrap_or_else(Utc::now);
+    let Ok(millilitres) = stock_at(&pool, at).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Query failed").into_response();
+    };
+
+    let unit = params.unit.unwrap_or(DEFAULT_UNIT);
+    Json(Stock {
+        amount: volume(millilitres, unit),
+        unit,
+        at,
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{postgres::PgConnectOptions, types::Uuid};
+
+    use super::*;
+
+    fn milk(unit: VolumeUnit, amount: f64) -> BucketUnit {
+        BucketUnit { unit, amount }
+    }
+
+    #[test]
+    fn entries_count_the_volume_moved() {
+        let pint = milk(VolumeUnit::Pints, 2.0);
+        assert_eq!(EntryKind::Withdrawal.change(pint), -1136.5225);
+        assert_eq!(EntryKind::Deposit.change(pint), 1136.5225);
+        assert_eq!(volume(1500.0, VolumeUnit::Liters), 1.5);
+        assert_eq!(volume(-236.5882365, VolumeUnit::Cups), -1.0);
+    }
+
+    #[test]
+    fn withdrawals_are_topped_up_to_what_they_need() {
+        let buckets = [
+            milk(VolumeUnit::Liters, 1.0),
+            milk(VolumeUnit::Milliliters, 500.0),
+        ];
+        assert!(top_up(1500.0, &buckets).is_none());
+        assert!(top_up(2000.0, &buckets).is_none());
+        let needed = top_up(1000.0, &buckets).unwrap();
+        assert_eq!(
+            (needed.unit, needed.amount),
+            (VolumeUnit::Millilitres, 500.0)
+        );
+        assert_eq!(top_up(-10.0, &buckets).unwrap().amount, 1500.0);
+        assert!(top_up(0.0, &[]).is_none());
+    }
+
+    /// Runs `test` against a fresh, migrated database on the server
+    /// `DATABASE_URL` names, and does nothing without one.
+    fn with_database<F: std::future::Future<Output = ()>>(test: impl FnOnce(PgPool) -> F) {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            return;
+        };
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let server = PgPool::connect(&url).await.unwrap();
+                let name = format!("milk_ledger_test_{}", Uuid::new_v4().simple());
+                sqlx::query(&format!("CREATE DATABASE {name}"))
+                    .execute(&server)
+                    .await
+                    .unwrap();
+                let options = url.parse::<PgConnectOptions>().unwrap().database(&name);
+                let pool = PgPool::connect_with(options).await.unwrap();
+                sqlx::migrate!().run(&pool).await.unwrap();
+                test(pool.clone()).await;
+                pool.close().await;
+                sqlx::query(&format!("DROP DATABASE {name}"))
+                    .execute(&server)
+                    .await
+                    .unwrap();
+            });
+    }
+
+    #[test]
+    fn balances_follow_recorded_volumes() {
+        with_database(|pool| async move {
+            let deposit = [milk(VolumeUnit::Liters, 2.0)];
+            record(&pool, EntryKind::Deposit, "santa", &deposit)
+                .await
+                .unwrap();
+            let withdrawal = [
+                milk(VolumeUnit::Liters, 1.5),
+                milk(VolumeUnit::Milliliters, 250.0),
+            ];
+            record(&pool, EntryKind::Withdrawal, "ip:127.0.0.1", &withdrawal)
+                .await
+                .unwrap();
+            assert_eq!(stock_at(&pool, Utc::now()).await.unwrap(), 250.0);
+
+            // More than is left is topped up, so the stock stays at zero.
+            let withdrawal = [milk(VolumeUnit::Liters, 1.0)];
+            record(&pool, EntryKind::Withdrawal, "ip:127.0.0.1", &withdrawal)
+                .await
+                .unwrap();
+            assert_eq!(stock_at(&pool, Utc::now()).await.unwrap(), 0.0);
+
+            let all = LedgerParams {
+                client: None,
+                from: None,
+                to: None,
+                unit: None,
+            };
+            let listed = entries(&pool, &all).await.unwrap();
+            let moves = listed
+                .iter()
+                .map(|entry| (entry.kind.as_str(), entry.client.as_str(), entry.balance))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                moves,
+                [
+                    ("deposit", "santa", 2000.0),
+                    ("withdrawal", "ip:127.0.0.1", 500.0),
+                    ("withdrawal", "ip:127.0.0.1", 250.0),
+                    ("deposit", SUPPLY, 1000.0),
+                    ("withdrawal", "ip:127.0.0.1", 0.0),
+                ]
+            );
+            assert_eq!(
+                (listed[3].amount, listed[3].unit.as_str()),
+                (750.0, "millilitres")
+            );
+
+            // Balances still count the entries filtered out.
+            let supply = LedgerParams {
+                client: Some(SUPPLY.to_string()),
+                ..all
+            };
+            let balances = entries(&pool, &supply).await.unwrap();
+            assert_eq!(balances.len(), 1);
+            assert_eq!(balances[0].balance, 1000.0);
+        });
+    }
+}
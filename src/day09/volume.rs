@@ -0,0 +1,347 @@
+//! Volume units and conversion between them.
+
+use std::{collections::HashMap, fmt};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
+
+/// A unit of volume, named as in the request body (e.g. `{"liters": 2}`).
+///
+/// Both spellings of the metric units are kept apart because they pick
+/// different default counterparts: `liters` pair with US gallons, `litres`
+/// with imperial pints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeUnit {
+    Milliliters,
+    Millilitres,
+    Liters,
+    Litres,
+    #[serde(alias = "us_cups")]
+    Cups,
+    #[serde(alias = "us_fluid_ounces")]
+    FluidOunces,
+    ImperialFluidOunces,
+    UsPints,
+    #[serde(alias = "imperial_pints")]
+    Pints,
+    #[serde(alias = "us_quarts")]
+    Quarts,
+    ImperialQuarts,
+    #[serde(alias = "us_gallons")]
+    Gallons,
+    ImperialGallons,
+}
+
+impl VolumeUnit {
+    /// Millilitres in one of this unit.
+    pub fn millilitres(self) -> f64 {
+        match self {
+            Self::Milliliters | Self::Millilitres => 1.0,
+            Self::Liters | Self::Litres => 1000.0,
+            Self::Cups => 236.5882365,
+            Self::FluidOunces => 29.5735295625,
+            Self::ImperialFluidOunces => 28.4130625,
+            Self::UsPints => 473.176473,
+            Self::Pints => 568.26125,
+            Self::Quarts => 946.352946,
+            Self::ImperialQuarts => 1136.5225,
+            Self::Gallons => 3785.411784,
+            Self::ImperialGallons => 4546.09,
+        }
+    }
+
+    /// The unit converted to when no target is asked for: US customary and
+    /// `-er` metric units map onto each other, as do imperial and `-re`
+    /// metric units.
+    fn counterpart(self) -> Self {
+        match self {
+            Self::Liters => Self::Gallons,
+            Self::Gallons | Self::Cups | Self::UsPints | Self::Quarts => Self::Liters,
+            Self::Milliliters => Self::FluidOunces,
+            Self::FluidOunces => Self::Milliliters,
+            Self::Litres => Self::Pints,
+            Self::Pints | Self::ImperialQuarts | Self::ImperialGallons => Self::Litres,
+            Self::Millilitres => Self::ImperialFluidOunces,
+            Self::ImperialFluidOunces => Self::Millilitres,
+        }
+    }
+}
+
+/// The unit's name as written in request bodies.
+impl fmt::Display for VolumeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(Value::String(name)) => f.write_str(&name),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// Halves are rounded away from zero.
+    #[default]
+    Nearest,
+    /// Halves are rounded to the even neighbour.
+    HalfEven,
+    Floor,
+    Ceil,
+    Truncate,
+}
+
+/// Most decimal places an `f64` amount can meaningfully be rounded to.
+const MAX_DECIMALS: u32 = 15;
+const OUT_OF_RANGE: &str = "Converted amount is out of range";
+
+impl Rounding {
+    pub fn round(self, value: f64, decimals: u32) -> Result<f64, String> {
+        if decimals > MAX_DECIMALS {
+            return Err(format!("Cannot round to more than {MAX_DECIMALS} decimals"));
+        }
+        let scale = 10f64.powi(decimals as i32);
+        let scaled = value * scale;
+        if !scaled.is_finite() {
+            return Err(OUT_OF_RANGE.to_string());
+        }
+        let rounded = match self {
+            Self::Nearest => scaled.round(),
+            Self::HalfEven => scaled.round_ties_even(),
+            Self::Floor => scaled.floor(),
+            Self::Ceil => scaled.ceil(),
+            Self::Truncate => scaled.trunc(),
+        };
+        Ok(rounded / scale)
+    }
+}
+
+/// An amount of milk, written as a single-key object such as `{"pints": 3}`.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(into = "HashMap<VolumeUnit, f64>")]
+pub struct BucketUnit {
+    pub unit: VolumeUnit,
+    pub amount: f64,
+}
+
+impl BucketUnit {
+    pub fn new(unit: VolumeUnit, amount: f64) -> Result<Self, String> {
+        if !amount.is_finite() || amount < 0.0 {
+            return Err("Amount must be a finite, non-negative number".to_string());
+        }
+        Ok(Self { unit, amount })
+    }
+
+    pub fn millilitres(self) -> f64 {
+        self.amount * self.unit.millilitres()
+    }
+
+    /// Convert to `to`, or to the unit's default counterpart.
+    pub fn convert(self, to: Option<VolumeUnit>) -> Result<Self, String> {
+        let to = to.unwrap_or(self.unit.counterpart());
+        Self::new(to, self.amount * self.unit.millilitres() / to.millilitres())
+            .map_err(|_| OUT_OF_RANGE.to_string())
+    }
+}
+
+impl From<BucketUnit> for HashMap<VolumeUnit, f64> {
+    fn from(bucket: BucketUnit) -> Self {
+        HashMap::from([(bucket.unit, bucket.amount)])
+    }
+}
+
+/// The buckets of a `/9/milk` request, in the order they were given.
+///
+/// A request is either an object, each key of which is a bucket
+/// (`{"liters": 1, "pints": 2}`), or an array of such objects. Entries are
+/// read straight off the request, so their order doesn't depend on how
+/// `serde_json` stores maps.
+pub struct MilkRequest {
+    pub buckets: Vec<BucketUnit>,
+    /// Whether the request was a lone single-unit object, which is answered
+    /// with a lone object rather than an array.
+    pub single: bool,
+}
+
+impl MilkRequest {
+    pub fn from_slice(body: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(body).map_err(|e| e.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for MilkRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RequestVisitor;
+
+        impl<'de> Visitor<'de> for RequestVisitor {
+            type Value = MilkRequest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object or an array of objects")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<MilkRequest, A::Error> {
+                let Buckets(buckets) = BucketsVisitor.visit_map(map)?;
+                Ok(MilkRequest {
+                    single: buckets.len() == 1,
+                    buckets,
+                })
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<MilkRequest, A::Error> {
+                let mut buckets = vec![];
+                while let Some(Buckets(object)) = seq.next_element()? {
+                    buckets.extend(object);
+                }
+                if buckets.is_empty() {
+                    return Err(de::Error::custom("Expected at least one bucket"));
+                }
+                Ok(MilkRequest {
+                    buckets,
+                    single: false,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(RequestVisitor)
+    }
+}
+
+/// The buckets of one request object, in the order of its keys.
+struct Buckets(Vec<BucketUnit>);
+
+struct BucketsVisitor;
+
+impl<'de> Visitor<'de> for BucketsVisitor {
+    type Value = Buckets;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object of amounts keyed by unit")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Buckets, A::Error> {
+        let mut buckets = vec![];
+        while let Some(unit) = map.next_key::<String>()? {
+            let unit = serde_json::from_value::<VolumeUnit>(Value::String(unit.clone()))
+                .map_err(|_| de::Error::custom(format!("Unknown unit `{unit}`")))?;
+            let amount = map
+                .next_value::<Value>()?
+                .as_f64()
+                .ok_or_else(|| de::Error::custom("Amount must be a number"))?;
+            buckets.push(BucketUnit::new(unit, amount).map_err(de::Error::custom)?);
+        }
+        if buckets.is_empty() {
+            return Err(de::Error::custom("Expected at least one unit"));
+        }
+        Ok(Buckets(buckets))
+    }
+}
+
+impl<'de> Deserialize<'de> for Buckets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BucketsVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(unit: VolumeUnit, amount: f64) -> BucketUnit {
+        BucketUnit::new(unit, amount).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn units_convert_to_their_counterparts() {
+        let gallons = bucket(VolumeUnit::Liters, 3.785411784)
+            .convert(None)
+            .unwrap();
+        assert_eq!(gallons.unit, VolumeUnit::Gallons);
+        assert_close(gallons.amount, 1.0);
+
+        let litres = bucket(VolumeUnit::Pints, 1.0).convert(None).unwrap();
+        assert_eq!(litres.unit, VolumeUnit::Litres);
+        assert_close(litres.amount, 0.56826125);
+
+        let cups = bucket(VolumeUnit::Liters, 0.2365882365)
+            .convert(Some(VolumeUnit::Cups))
+            .unwrap();
+        assert_close(cups.amount, 1.0);
+
+        for unit in [VolumeUnit::Milliliters, VolumeUnit::ImperialFluidOunces] {
+            let back = bucket(unit, 12.5).convert(None).unwrap().convert(None);
+            assert_eq!(back.unwrap().unit, unit);
+        }
+    }
+
+    #[test]
+    fn out_of_range_amounts_are_refused() {
+        assert!(BucketUnit::new(VolumeUnit::Liters, -1.0).is_err());
+        assert!(BucketUnit::new(VolumeUnit::Liters, f64::NAN).is_err());
+        let huge = bucket(VolumeUnit::ImperialGallons, f64::MAX);
+        assert_eq!(
+            huge.convert(Some(VolumeUnit::Milliliters)).unwrap_err(),
+            OUT_OF_RANGE
+        );
+        assert_eq!(
+            Rounding::Nearest.round(f64::MAX, 2).unwrap_err(),
+            OUT_OF_RANGE
+        );
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let round = |rounding: Rounding, value| rounding.round(value, 1).unwrap();
+        assert_close(round(Rounding::Nearest, 0.25), 0.3);
+        assert_close(round(Rounding::Nearest, -0.25), -0.3);
+        assert_close(round(Rounding::HalfEven, 0.25), 0.2);
+        assert_close(round(Rounding::Floor, -0.21), -0.3);
+        assert_close(round(Rounding::Ceil, 0.21), 0.3);
+        assert_close(round(Rounding::Truncate, -0.29), -0.2);
+        assert!(Rounding::Nearest.round(1.0, MAX_DECIMALS + 1).is_err());
+    }
+
+    #[test]
+    fn requests_keep_bucket_order() {
+        let body = br#"[{"pints": 1}, {"quarts": 2, "cups": 3, "liters": 4}]"#;
+        let request = MilkRequest::from_slice(body).unwrap();
+        let units = request.buckets.iter().map(|bucket| bucket.unit);
+        assert_eq!(
+            units.collect::<Vec<_>>(),
+            [
+                VolumeUnit::Pints,
+                VolumeUnit::Quarts,
+                VolumeUnit::Cups,
+                VolumeUnit::Liters
+            ]
+        );
+        assert!(!request.single);
+
+        let single = MilkRequest::from_slice(br#"{"liters": 1}"#).unwrap();
+        assert!(single.single);
+        let pair = MilkRequest::from_slice(br#"{"liters": 1, "cups": 2}"#).unwrap();
+        assert!(!pair.single);
+        for invalid in [
+            "[]",
+            "{}",
+            "[{}]",
+            r#"{"buckets": 1}"#,
+            r#"{"liters": "1"}"#,
+            r#"{"liters": -1}"#,
+            "[1]",
+            "1",
+        ] {
+            assert!(MilkRequest::from_slice(invalid.as_bytes()).is_err());
+        }
+    }
+}
@@ -34,18 +34,14 @@ impl fmt::Display for Team {
     }
 }
 
+#[derive(Default)]
 enum GameState {
     Won(Team),
     Stalemate,
+    #[default]
     NotYetWon,
 }
 
-impl Default for GameState {
-    fn default() -> Self {
-        Self::NotYetWon
-    }
-}
-
 /// Create an `N`x`N` board, with an extra layer of walls (left, right and bottom).
 /// Implemented using const generics.
 pub struct Board<const N: usize = 4> {
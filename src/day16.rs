@@ -133,6 +133,7 @@ pub async fn unwrap(State(state): State<AppState>, header: HeaderMap) -> impl In
     (StatusCode::OK, payload)
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 struct SimpleClaims {
     // sub: String,
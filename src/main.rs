@@ -1,3 +1,4 @@
+mod auth;
 mod day00;
 mod day02;
 mod day05;
@@ -6,15 +7,18 @@ mod day12;
 mod day16;
 mod day19;
 mod day23;
+mod rate_limit;
 
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{delete, get, post, put},
     Router,
 };
-use leaky_bucket::RateLimiter;
 use rand::SeedableRng;
+use rate_limit::{Backend, ClientIdentifier, KeyedLimiter, Policy, RateLimitLayer};
+use shuttle_runtime::{tokio::net::TcpListener, CustomError};
 use tokio::sync::RwLock;
 use tower_http::services::ServeDir;
 
@@ -22,7 +26,7 @@ type AppState = Arc<RwLock<InnerAppState>>;
 
 struct InnerAppState {
     board: day12::Board,
-    rate_limiter: RateLimiter,
+    milk_limiter: rate_limit::SharedLimiter,
     rng: rand::rngs::StdRng,
     secrets: shuttle_runtime::SecretStore,
     pool: sqlx::PgPool,
@@ -30,10 +34,14 @@ struct InnerAppState {
 }
 
 impl InnerAppState {
-    fn new(secrets: shuttle_runtime::SecretStore, pool: sqlx::PgPool) -> Self {
+    fn new(
+        secrets: shuttle_runtime::SecretStore,
+        pool: sqlx::PgPool,
+        milk_limiter: rate_limit::SharedLimiter,
+    ) -> Self {
         Self {
             board: day12::Board::<4>::new(),
-            rate_limiter: Self::default_rate_limiter(),
+            milk_limiter,
             rng: Self::default_rng(),
             secrets,
             pool,
@@ -45,20 +53,26 @@ impl InnerAppState {
         self.rng = Self::default_rng();
     }
 
-    fn reload_rate_limiter(&mut self) {
-        self.rate_limiter = Self::default_rate_limiter()
+    fn default_rng() -> rand::rngs::StdRng {
+        rand::rngs::StdRng::seed_from_u64(2024)
     }
+}
 
-    fn default_rate_limiter() -> RateLimiter {
-        RateLimiter::builder()
-            .initial(5)
-            .max(5)
-            .interval(Duration::from_secs(1))
-            .build()
-    }
+/// Serves the router with connection info, which `shuttle_axum` leaves out, so
+/// that the rate limiter knows which peer it is talking to.
+struct AppService(Router);
 
-    fn default_rng() -> rand::rngs::StdRng {
-        rand::rngs::StdRng::seed_from_u64(2024)
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for AppService {
+    async fn bind(self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = TcpListener::bind(addr).await.map_err(CustomError::new)?;
+        axum::serve(
+            listener,
+            self.0.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .map_err(CustomError::new)?;
+        Ok(())
     }
 }
 
@@ -66,14 +80,37 @@ impl InnerAppState {
 async fn main(
     #[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore,
     #[shuttle_shared_db::Postgres] pool: sqlx::PgPool,
-) -> shuttle_axum::ShuttleAxum {
+) -> Result<AppService, shuttle_runtime::Error> {
     // Stand up database
     sqlx::migrate!()
         .run(&pool)
         .await
         .expect("Failed to migrate database");
 
-    let state = Arc::new(RwLock::new(InnerAppState::new(secrets, pool)));
+    let policies_path = secrets
+        .get(day05::POLICIES_PATH)
+        .or_else(|| std::env::var(day05::POLICIES_PATH).ok());
+    day05::load_policies(policies_path.as_deref()).expect("Failed to load manifest policies");
+
+    let identifier = Arc::new(ClientIdentifier::new(&secrets));
+    let backend = Backend::new(&secrets, &pool);
+    let milk_limiter =
+        KeyedLimiter::shared(day09::MILK_POLICY, identifier.clone(), &backend, &pool);
+    let strict_limiter = KeyedLimiter::shared(Policy::STRICT, identifier, &backend, &pool);
+
+    let state = Arc::new(RwLock::new(InnerAppState::new(
+        secrets,
+        pool,
+        milk_limiter.clone(),
+    )));
+    // Rate limited route groups, merged into the router below.
+    let milk_routes = Router::new()
+        .route("/9/milk", post(day09::milk))
+        .layer(RateLimitLayer::new(milk_limiter));
+    let strict_routes = Router::new()
+        .route("/16/wrap", post(day16::wrap))
+        .route("/19/draft", post(day19::draft))
+        .layer(RateLimitLayer::new(strict_limiter));
     let router = Router::new()
         .route("/", get(day00::hello_world))
         .route("/-1/seek", get(day00::seek))
@@ -81,27 +118,47 @@ async fn main(
         .route("/2/key", get(day02::key))
         .route("/2/v6/dest", get(day02::dest_v6))
         .route("/2/v6/key", get(day02::key_v6))
+        .route("/2/any/dest", get(day02::dest_any))
+        .route("/2/any/key", get(day02::key_any))
+        .route("/2/mac/dest", get(day02::dest_mac))
+        .route("/2/mac/key", get(day02::key_mac))
+        .route("/2/batch", post(day02::batch))
+        .route("/2/anonymize", get(day02::anonymize))
+        .route("/2/deanonymize", get(day02::deanonymize))
         .route("/5/manifest", post(day05::manifest))
-        .route("/9/milk", post(day09::milk))
+        .route("/5/lint", post(day05::lint))
+        .route("/5/analyze", post(day05::analyze_manifest))
+        .route("/5/compat", post(day05::compat))
+        .route(
+            "/5/batch",
+            post(day05::batch).layer(DefaultBodyLimit::max(day05::BATCH_BODY_LIMIT)),
+        )
+        .route("/5/orders", get(day05::orders))
+        .route("/5/orders/totals", get(day05::order_totals))
+        .route("/5/convert", post(day05::convert))
         .route("/9/refill", post(day09::refill))
+        .route("/9/bucket", get(day09::bucket).put(day09::update_bucket))
+        .route("/9/bucket/audit", get(day09::bucket_audit_log))
+        .route("/9/ledger", get(day09::ledger))
+        .route("/9/stock", get(day09::stock))
         .route("/12/board", get(day12::board))
         .route("/12/random-board", get(day12::random_board))
         .route("/12/reset", post(day12::reset))
         .route("/12/place/:team/:column", post(day12::place))
-        .route("/16/wrap", post(day16::wrap))
         .route("/16/unwrap", get(day16::unwrap))
         .route("/16/decode", post(day16::decode))
         .route("/19/reset", post(day19::reset))
         .route("/19/cite/:id", get(day19::cite))
         .route("/19/remove/:id", delete(day19::remove))
         .route("/19/undo/:id", put(day19::undo))
-        .route("/19/draft", post(day19::draft))
         .route("/19/list", get(day19::list))
         .route("/23/star", get(day23::star))
         .route("/23/present/:color", get(day23::present))
         .route("/23/ornament/:state/:n", get(day23::ornament))
         .route("/23/lockfile", post(day23::lockfile))
+        .merge(milk_routes)
+        .merge(strict_routes)
         .with_state(state)
         .nest_service("/assets", ServeDir::new("assets"));
-    Ok(router.into())
+    Ok(AppService(router))
 }
@@ -0,0 +1,1047 @@
+//! Per-client rate limiting, applied to a group of routes as a tower layer.
+//!
+//! Each group gets a [`Policy`] and its own [`SharedLimiter`], which keeps a
+//! limiter per client. Handlers that need to reach into the limiter (e.g. to
+//! refill it) keep a clone of the [`SharedLimiter`] in the app state.
+//!
+//! Limiter state lives in memory unless the `postgres` [`Backend`] is
+//! configured, in which case it is shared by every replica. Algorithms
+//! changed at runtime are always kept in Postgres, so every replica uses them
+//! and they survive a restart.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{
+    types::{
+        chrono::{DateTime, Utc},
+        Json,
+    },
+    PgPool, Postgres, Transaction,
+};
+use tokio::sync::{Mutex, RwLock};
+use tower::{Layer, Service};
+
+/// `memory` (the default) or `postgres`; see [`Backend`].
+const BACKEND: &str = "RATE_LIMIT_BACKEND";
+/// Comma-separated CIDRs of proxies whose `X-Forwarded-For` is believed.
+const TRUSTED_PROXIES: &str = "RATE_LIMIT_TRUSTED_PROXIES";
+/// Comma-separated API keys that may be sent in `X-Api-Key` to get a limit
+/// of their own regardless of address.
+const API_KEYS: &str = "RATE_LIMIT_API_KEYS";
+/// Key for the fingerprints API keys are stored and reported as. Required
+/// when API keys are configured.
+const KEY_SECRET: &str = "RATE_LIMIT_KEY_SECRET";
+const API_KEY_HEADER: &str = "x-api-key";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+/// How often limiters left idle long enough to recover are dropped.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Largest body buffered to work out a request's cost.
+const COST_BODY_LIMIT: usize = 2 * 1024 * 1024;
+/// How long an algorithm is used before checking whether another replica
+/// changed it.
+const ALGORITHM_TTL: Duration = Duration::from_secs(1);
+
+/// Who a request is counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Client {
+    /// A fingerprint of the key, so the key itself is never stored or shown.
+    ApiKey(String),
+    Ip(IpAddr),
+    /// The request came without connection info, so its address could not
+    /// be determined.
+    Unknown,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ApiKey(key) => write!(f, "key:{key}"),
+            Self::Ip(addr) => write!(f, "ip:{addr}"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Works out the [`Client`] behind a request.
+pub struct ClientIdentifier {
+    trusted_proxies: Vec<IpNet>,
+    api_keys: Vec<String>,
+    key_mac: Hmac<Sha256>,
+}
+
+impl ClientIdentifier {
+    pub fn new(secrets: &shuttle_runtime::SecretStore) -> Self {
+        let list = |key| {
+            secrets
+                .get(key)
+                .map(|list| {
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(str::to_string)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        let trusted_proxies = list(TRUSTED_PROXIES)
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .expect("Invalid trusted proxy")
+            })
+            .collect();
+        let api_keys = list(API_KEYS);
+        let key_secret = match secrets.get(KEY_SECRET) {
+            Some(secret) => secret,
+            None if api_keys.is_empty() => String::new(),
+            None => panic!("`{KEY_SECRET}` is required with `{API_KEYS}`"),
+        };
+        Self {
+            trusted_proxies,
+            api_keys,
+            key_mac: Hmac::new_from_slice(key_secret.as_bytes())
+                .expect("HMAC accepts any key length"),
+        }
+    }
+
+    /// The client an API key stands for, identified by its fingerprint.
+    pub fn api_key(&self, key: &str) -> Client {
+        let mut mac = self.key_mac.clone();
+        mac.update(key.as_bytes());
+        let fingerprint = mac.finalize().into_bytes();
+        Client::ApiKey(
+            fingerprint[..16]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        )
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(&addr))
+    }
+
+    /// Identify the client from its API key or, failing that, its address.
+    ///
+    /// `X-Forwarded-For` is walked from the right for as long as the hop that
+    /// appended the entry is a trusted proxy, starting from the peer. Behind
+    /// the platform's proxy the peer is that proxy, so it has to be listed
+    /// in `RATE_LIMIT_TRUSTED_PROXIES` for forwarded addresses to count.
+    /// Without connection info no hop can be vouched for, so the header is
+    /// not believed and the client is unknown.
+    fn identify(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Result<Client, ()> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            return match key.to_str() {
+                Ok(key) if self.api_keys.iter().any(|known| known == key) => Ok(self.api_key(key)),
+                _ => Err(()),
+            };
+        }
+
+        let Some(mut client) = peer else {
+            return Ok(Client::Unknown);
+        };
+        let forwarded = headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let mut hops = forwarded.into_iter().rev();
+        while self.is_trusted(client) {
+            match hops.next() {
+                Some(Some(hop)) => client = hop,
+                // Nothing usable was forwarded, so the proxy is the client.
+                _ => break,
+            }
+        }
+        Ok(Client::Ip(client))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Up to `capacity` tokens, topped up with `refill` tokens every
+    /// `interval`.
+    TokenBucket {
+        capacity: usize,
+        refill: usize,
+        interval: Duration,
+    },
+    /// At most `limit` requests in any `window`.
+    SlidingWindow { limit: usize, window: Duration },
+}
+
+impl Algorithm {
+    /// The most a single request can cost and still be let through.
+    fn max_permits(&self) -> usize {
+        match *self {
+            Self::TokenBucket { capacity, .. } => capacity,
+            Self::SlidingWindow { limit, .. } => limit,
+        }
+    }
+
+    /// How long a limiter left alone takes to be fully restored, however
+    /// drained it was. After that it is no different from a new one.
+    fn recovery_time(&self) -> Duration {
+        match *self {
+            Self::TokenBucket {
+                capacity,
+                refill,
+                interval,
+            } => {
+                let intervals = capacity.div_ceil(refill.max(1));
+                interval.saturating_mul(u32::try_from(intervals).unwrap_or(u32::MAX))
+            }
+            Self::SlidingWindow { window, .. } => window,
+        }
+    }
+}
+
+/// How a route group is limited, and what a rejected client is told.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// Distinguishes the group's limiters in a shared backend.
+    pub name: &'static str,
+    pub algorithm: Algorithm,
+    pub rejection: &'static str,
+    /// What a request costs, worked out from its headers and body. Requests
+    /// cost one if unset, and their body is left unread. A request costing
+    /// more than the limit could ever allow is refused with a 413.
+    pub cost: Option<fn(&HeaderMap, &[u8]) -> usize>,
+}
+
+impl Policy {
+    /// For endpoints that sign or write: ten requests a minute.
+    pub const STRICT: Self = Self {
+        name: "strict",
+        algorithm: Algorithm::SlidingWindow {
+            limit: 10,
+            window: Duration::from_secs(60),
+        },
+        rejection: "Too many requests\n",
+        cost: None,
+    };
+}
+
+/// Time since the Unix epoch, so that limiter state means the same thing on
+/// every replica sharing it.
+type Timestamp = Duration;
+
+fn now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// The part of a limiter that changes with each request. The limits
+/// themselves come from the [`Algorithm`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LimiterState {
+    TokenBucket {
+        level: usize,
+        /// When the last whole interval was credited.
+        last_refill: Timestamp,
+    },
+    SlidingWindow {
+        /// When each request still inside the window was let through.
+        hits: VecDeque<Timestamp>,
+    },
+}
+
+impl LimiterState {
+    fn new(algorithm: &Algorithm, now: Timestamp) -> Self {
+        match *algorithm {
+            Algorithm::TokenBucket { capacity, .. } => Self::TokenBucket {
+                level: capacity,
+                last_refill: now,
+            },
+            Algorithm::SlidingWindow { .. } => Self::SlidingWindow {
+                hits: VecDeque::new(),
+            },
+        }
+    }
+
+    /// Credit refills and forget expired hits up to `now`. State left over
+    /// from a different algorithm is started afresh.
+    fn update(&mut self, algorithm: &Algorithm, now: Timestamp) {
+        match (algorithm, &mut *self) {
+            (
+                &Algorithm::TokenBucket {
+                    capacity,
+                    refill,
+                    interval,
+                },
+                Self::TokenBucket { level, last_refill },
+            ) => {
+                let intervals = now.saturating_sub(*last_refill).as_nanos() / interval.as_nanos();
+                let intervals = u32::try_from(intervals).unwrap_or(u32::MAX);
+                let credited = level.saturating_add(refill.saturating_mul(intervals as usize));
+                if credited >= capacity {
+                    *level = capacity;
+                    *last_refill = now;
+                } else {
+                    *level = credited;
+                    *last_refill = last_refill.saturating_add(interval.saturating_mul(intervals));
+                }
+            }
+            (&Algorithm::SlidingWindow { window, .. }, Self::SlidingWindow { hits }) => {
+                while hits
+                    .front()
+                    .is_some_and(|&hit| now.saturating_sub(hit) >= window)
+                {
+                    hits.pop_front();
+                }
+            }
+            _ => *self = Self::new(algorithm, now),
+        }
+    }
+
+    fn try_acquire(&mut self, algorithm: &Algorithm, permits: usize, now: Timestamp) -> bool {
+        self.update(algorithm, now);
+        match (algorithm, self) {
+            (Algorithm::TokenBucket { .. }, Self::TokenBucket { level, .. }) => {
+                if *level < permits {
+                    return false;
+                }
+                *level -= permits;
+            }
+            (&Algorithm::SlidingWindow { limit, .. }, Self::SlidingWindow { hits }) => {
+                if permits > limit.saturating_sub(hits.len()) {
+                    return false;
+                }
+                hits.extend(std::iter::repeat_n(now, permits));
+            }
+            _ => unreachable!("state was just updated for this algorithm"),
+        }
+        true
+    }
+
+    /// Undo a successful [`Self::try_acquire`] of `permits`.
+    fn release(&mut self, algorithm: &Algorithm, permits: usize, now: Timestamp) {
+        self.update(algorithm, now);
+        match (algorithm, self) {
+            (&Algorithm::TokenBucket { capacity, .. }, Self::TokenBucket { level, .. }) => {
+                *level = capacity.min(level.saturating_add(permits));
+            }
+            (Algorithm::SlidingWindow { .. }, Self::SlidingWindow { hits }) => {
+                hits.truncate(hits.len().saturating_sub(permits));
+            }
+            _ => unreachable!("state was just updated for this algorithm"),
+        }
+    }
+
+    /// The state as reported after a request for `permits`.
+    fn status(&self, algorithm: &Algorithm, permits: usize, now: Timestamp) -> RateLimitStatus {
+        match (algorithm, self) {
+            (
+                &Algorithm::TokenBucket {
+                    capacity,
+                    refill,
+                    interval,
+                },
+                &Self::TokenBucket { level, last_refill },
+            ) => {
+                // Time until `permits` tokens are available.
+                let wait_for = |permits: usize| {
+                    let intervals = permits.saturating_sub(level).div_ceil(refill);
+                    let intervals = u32::try_from(intervals).unwrap_or(u32::MAX);
+                    interval
+                        .saturating_mul(intervals)
+                        .saturating_sub(now.saturating_sub(last_refill))
+                };
+                RateLimitStatus {
+                    limit: capacity,
+                    remaining: level,
+                    reset: wait_for(capacity),
+                    retry_after: wait_for(permits.max(1)),
+                }
+            }
+            (&Algorithm::SlidingWindow { limit, window }, Self::SlidingWindow { hits }) => {
+                // Time until the `n`th oldest hit leaves the window.
+                let expiry = |n: usize| match n.checked_sub(1).and_then(|index| hits.get(index)) {
+                    Some(&hit) => window.saturating_sub(now.saturating_sub(hit)),
+                    None => Duration::ZERO,
+                };
+                let remaining = limit.saturating_sub(hits.len());
+                RateLimitStatus {
+                    limit,
+                    remaining,
+                    reset: expiry(hits.len()),
+                    retry_after: match hits.len().saturating_add(permits.max(1)).checked_sub(limit)
+                    {
+                        Some(excess) if excess > 0 => expiry(excess),
+                        _ => Duration::ZERO,
+                    },
+                }
+            }
+            _ => unreachable!("status is only taken after an update"),
+        }
+    }
+}
+
+/// A limiter's state after a request, as reported in response headers.
+pub struct RateLimitStatus {
+    limit: usize,
+    pub remaining: usize,
+    /// Until the limit is fully restored.
+    reset: Duration,
+    /// Until the next request would be let through.
+    retry_after: Duration,
+}
+
+impl RateLimitStatus {
+    /// Whole seconds, rounded up so clients never retry too early.
+    fn seconds(duration: Duration) -> u64 {
+        duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+    }
+
+    /// Add the IETF draft `RateLimit-*` headers, and `Retry-After` to a 429.
+    fn apply(&self, mut response: Response) -> Response {
+        let headers = response.headers_mut();
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert(
+            "ratelimit-reset",
+            HeaderValue::from(Self::seconds(self.reset)),
+        );
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(Self::seconds(self.retry_after)),
+            );
+        }
+        response
+    }
+}
+
+/// Where limiter state is kept, chosen with the `RATE_LIMIT_BACKEND` secret.
+pub enum Backend {
+    /// In this process only; each replica enforces its own limits.
+    Memory,
+    /// In the `rate_limits` table, so limits hold across replicas.
+    Postgres(PgPool),
+}
+
+impl Backend {
+    pub fn new(secrets: &shuttle_runtime::SecretStore, pool: &PgPool) -> Self {
+        match secrets.get(BACKEND).as_deref() {
+            None | Some("memory") => Self::Memory,
+            Some("postgres") => Self::Postgres(pool.clone()),
+            Some(backend) => panic!("Unknown rate limit backend `{backend}`"),
+        }
+    }
+}
+
+struct Entry {
+    state: LimiterState,
+    last_used: Timestamp,
+}
+
+/// The algorithm in force, and when it was read from the database.
+struct CachedAlgorithm {
+    algorithm: Algorithm,
+    fetched_at: Timestamp,
+}
+
+enum Store {
+    Memory(Mutex<HashMap<Client, Entry>>),
+    Postgres(PgPool),
+}
+
+/// A limiter per client, created on first use and evicted once idle.
+pub struct KeyedLimiter {
+    policy: Policy,
+    /// The policy's, unless changed at runtime. Changes are kept in the
+    /// `rate_limit_algorithms` table and cached here for [`ALGORITHM_TTL`].
+    algorithm: RwLock<Option<CachedAlgorithm>>,
+    identifier: Arc<ClientIdentifier>,
+    store: Store,
+    pool: PgPool,
+    last_eviction: Mutex<Timestamp>,
+}
+
+pub type SharedLimiter = Arc<KeyedLimiter>;
+
+impl KeyedLimiter {
+    pub fn shared(
+        policy: Policy,
+        identifier: Arc<ClientIdentifier>,
+        backend: &Backend,
+        pool: &PgPool,
+    ) -> SharedLimiter {
+        let store = match backend {
+            Backend::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            Backend::Postgres(pool) => Store::Postgres(pool.clone()),
+        };
+        Arc::new(Self {
+            policy,
+            algorithm: RwLock::new(None),
+            identifier,
+            store,
+            pool: pool.clone(),
+            last_eviction: Mutex::new(now()),
+        })
+    }
+
+    /// Drop the limiters idle for longer than `algorithm` takes to restore
+    /// them, so recreating one lets no extra request through.
+    async fn evict_idle(&self, algorithm: &Algorithm, now: Timestamp) -> sqlx::Result<()> {
+        let mut last_eviction = self.last_eviction.lock().await;
+        if now.saturating_sub(*last_eviction) < EVICTION_INTERVAL {
+            return Ok(());
+        }
+        *last_eviction = now;
+        let idle_timeout = algorithm.recovery_time();
+        match &self.store {
+            Store::Memory(entries) => entries
+                .lock()
+                .await
+                .retain(|_, entry| now.saturating_sub(entry.last_used) <= idle_timeout),
+            Store::Postgres(pool) => {
+                sqlx::query!(
+                    "DELETE FROM rate_limits
+                        WHERE scope = $1 AND updated_at < NOW() - MAKE_INTERVAL(secs => $2)",
+                    self.policy.name,
+                    idle_timeout.as_secs_f64(),
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f` on a client's state, creating it if need be, and store the
+    /// result.
+    async fn modify<T>(
+        &self,
+        client: &Client,
+        f: impl FnOnce(&mut LimiterState, &Algorithm, Timestamp) -> T,
+    ) -> sqlx::Result<T> {
+        let algorithm = &self.algorithm().await;
+        let now = now();
+        self.evict_idle(algorithm, now).await?;
+        match &self.store {
+            Store::Memory(entries) => {
+                let mut entries = entries.lock().await;
+                let entry = entries.entry(client.clone()).or_insert_with(|| Entry {
+                    state: LimiterState::new(algorithm, now),
+                    last_used: now,
+                });
+                entry.last_used = now;
+                Ok(f(&mut entry.state, algorithm, now))
+            }
+            Store::Postgres(pool) => {
+                // Make sure the client has a row, then lock it for the
+                // read-modify-write. The time comes from the database so every
+                // replica agrees on it.
+                let mut transaction = pool.begin().await?;
+                sqlx::query!(
+                    "INSERT INTO rate_limits (scope, client, state) VALUES ($1, $2, $3)
+                        ON CONFLICT (scope, client) DO NOTHING",
+                    self.policy.name,
+                    client.to_string(),
+                    Json(LimiterState::new(algorithm, now)) as _,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                let row = sqlx::query!(
+                    r#"SELECT state AS "state: Json<LimiterState>", CLOCK_TIMESTAMP() AS "now!"
+                        FROM rate_limits
+                        WHERE scope = $1 AND client = $2
+                        FOR UPDATE"#,
+                    self.policy.name,
+                    client.to_string(),
+                )
+                .fetch_one(&mut *transaction)
+                .await?;
+                let Json(mut state) = row.state;
+                let result = f(&mut state, algorithm, timestamp(row.now));
+                sqlx::query!(
+                    "UPDATE rate_limits SET state = $3, updated_at = NOW()
+                        WHERE scope = $1 AND client = $2",
+                    self.policy.name,
+                    client.to_string(),
+                    Json(&state) as _,
+                )
+                .execute(&mut *transaction)
+                .await?;
+                transaction.commit().await?;
+                Ok(result)
+            }
+        }
+    }
+
+    async fn try_acquire(
+        &self,
+        client: &Client,
+        permits: usize,
+    ) -> sqlx::Result<(bool, RateLimitStatus)> {
+        self.modify(client, |state, algorithm, now| {
+            let acquired = state.try_acquire(algorithm, permits, now);
+            (acquired, state.status(algorithm, permits, now))
+        })
+        .await
+    }
+
+    /// Give back `permits` a client was charged for a request that then
+    /// failed.
+    pub async fn refund(&self, client: &Client, permits: usize) -> sqlx::Result<()> {
+        self.modify(client, |state, algorithm, now| {
+            state.release(algorithm, permits, now)
+        })
+        .await
+    }
+
+    /// The algorithm in force. Should the database be unreachable, the last
+    /// one read is kept, or the policy's if none was, and reading it again is
+    /// left until [`ALGORITHM_TTL`] has passed.
+    pub async fn algorithm(&self) -> Algorithm {
+        let now = now();
+        let cached = self
+            .algorithm
+            .read()
+            .await
+            .as_ref()
+            .map(|cached| (cached.algorithm, cached.fetched_at));
+        if let Some((algorithm, fetched_at)) = cached {
+            if now.saturating_sub(fetched_at) < ALGORITHM_TTL {
+                return algorithm;
+            }
+        }
+        let stored = sqlx::query_scalar!(
+            r#"SELECT algorithm AS "algorithm: Json<Algorithm>"
+                FROM rate_limit_algorithms WHERE scope = $1"#,
+            self.policy.name,
+        )
+        .fetch_optional(&self.pool)
+        .await;
+        let algorithm = match stored {
+            Ok(Some(Json(algorithm))) => algorithm,
+            Ok(None) => self.policy.algorithm,
+            Err(_) => cached.map_or(self.policy.algorithm, |(algorithm, _)| algorithm),
+        };
+        *self.algorithm.write().await = Some(CachedAlgorithm {
+            algorithm,
+            fetched_at: now,
+        });
+        algorithm
+    }
+
+    /// Change the limits for every client, committing `transaction` along
+    /// with the change. Other replicas pick it up within [`ALGORITHM_TTL`].
+    ///
+    /// Existing state is kept, so a token bucket keeps its level (up to the
+    /// new capacity); switching to a different kind of algorithm starts every
+    /// client afresh.
+    pub async fn set_algorithm(
+        &self,
+        mut transaction: Transaction<'_, Postgres>,
+        algorithm: Algorithm,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO rate_limit_algorithms (scope, algorithm) VALUES ($1, $2)
+                ON CONFLICT (scope) DO UPDATE SET algorithm = $2, updated_at = NOW()",
+            self.policy.name,
+            Json(algorithm) as _,
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        *self.algorithm.write().await = Some(CachedAlgorithm {
+            algorithm,
+            fetched_at: now(),
+        });
+        Ok(())
+    }
+
+    pub fn identify(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Result<Client, ()> {
+        self.identifier.identify(peer, headers)
+    }
+
+    pub fn api_key(&self, key: &str) -> Client {
+        self.identifier.api_key(key)
+    }
+
+    /// A client's current state, without counting a request against it or
+    /// storing anything. A client without a limiter is reported as fresh.
+    pub async fn peek(&self, client: &Client) -> sqlx::Result<RateLimitStatus> {
+        let algorithm = &self.algorithm().await;
+        let (state, now) = match &self.store {
+            Store::Memory(entries) => {
+                let entries = entries.lock().await;
+                (entries.get(client).map(|entry| entry.state.clone()), now())
+            }
+            Store::Postgres(pool) => {
+                let row = sqlx::query!(
+                    r#"SELECT
+                        (SELECT state FROM rate_limits WHERE scope = $1 AND client = $2)
+                            AS "state?: Json<LimiterState>",
+                        CLOCK_TIMESTAMP() AS "now!""#,
+                    self.policy.name,
+                    client.to_string(),
+                )
+                .fetch_one(pool)
+                .await?;
+                (row.state.map(|Json(state)| state), timestamp(row.now))
+            }
+        };
+        let mut state = state.unwrap_or_else(|| LimiterState::new(algorithm, now));
+        state.update(algorithm, now);
+        Ok(state.status(algorithm, 0, now))
+    }
+
+    /// Restore a single client's limit, or every client's, returning how
+    /// many permits that gave back.
+    pub async fn reset(&self, client: Option<&Client>) -> sqlx::Result<usize> {
+        let algorithm = &self.algorithm().await;
+        // A missing limiter is created afresh on next use.
+        let (removed, now): (Vec<LimiterState>, _) = match &self.store {
+            Store::Memory(entries) => {
+                let mut entries = entries.lock().await;
+                let removed = match client {
+                    Some(client) => entries.remove(client).into_iter().collect(),
+                    None => entries.drain().map(|(_, entry)| entry).collect::<Vec<_>>(),
+                };
+                (
+                    removed.into_iter().map(|entry| entry.state).collect(),
+                    now(),
+                )
+            }
+            Store::Postgres(pool) => {
+                let rows = sqlx::query!(
+                    r#"DELETE FROM rate_limits
+                        WHERE scope = $1 AND ($2::TEXT IS NULL OR client = $2)
+                        RETURNING state AS "state: Json<LimiterState>", CLOCK_TIMESTAMP() AS "now!""#,
+                    self.policy.name,
+                    client.map(Client::to_string),
+                )
+                .fetch_all(pool)
+                .await?;
+                let now = rows.first().map_or_else(now, |row| timestamp(row.now));
+                let removed = rows.into_iter().map(|row| row.state.0);
+                (removed.collect(), now)
+            }
+        };
+        Ok(removed
+            .into_iter()
+            .map(|mut state| {
+                state.update(algorithm, now);
+                let remaining = state.status(algorithm, 0, now).remaining;
+                algorithm.max_permits().saturating_sub(remaining)
+            })
+            .fold(0, usize::saturating_add))
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> Timestamp {
+    Duration::from_micros(time.timestamp_micros().max(0) as u64)
+}
+
+fn unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Rate limiter unavailable\n",
+    )
+        .into_response()
+}
+
+/// Applies a [`SharedLimiter`] to every request of the routes it wraps.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: SharedLimiter,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: SharedLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: SharedLimiter,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Take the service that was driven to readiness, leaving a clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let peer = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            let Ok(client) = limiter.identify(peer, request.headers()) else {
+                return Ok((StatusCode::UNAUTHORIZED, "Invalid API key\n").into_response());
+            };
+            let (request, permits) = match limiter.policy.cost {
+                Some(cost) => {
+                    // Buffer the body to price the request, then hand it on.
+                    let (parts, body) = request.into_parts();
+                    let Ok(bytes) = to_bytes(body, COST_BODY_LIMIT).await else {
+                        return Ok(
+                            (StatusCode::PAYLOAD_TOO_LARGE, "Request too large\n").into_response()
+                        );
+                    };
+                    let permits = cost(&parts.headers, &bytes);
+                    let algorithm = limiter.algorithm().await;
+                    if permits > algorithm.max_permits() {
+                        let max = algorithm.max_permits();
+                        return Ok((
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            format!("Batch too large, at most {max} allowed\n"),
+                        )
+                            .into_response());
+                    }
+                    (Request::from_parts(parts, Body::from(bytes)), permits)
+                }
+                None => (request, 1),
+            };
+            let Ok((acquired, status)) = limiter.try_acquire(&client, permits).await else {
+                return Ok(unavailable());
+            };
+            if !acquired {
+                return Ok(status.apply(
+                    (StatusCode::TOO_MANY_REQUESTS, limiter.policy.rejection).into_response(),
+                ));
+            }
+            // Let handlers know who they are serving.
+            let mut request = request;
+            request.extensions_mut().insert(client);
+            let response = inner.call(request).await?;
+            Ok(status.apply(response))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identifier() -> ClientIdentifier {
+        ClientIdentifier {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            api_keys: vec!["secret".to_string()],
+            key_mac: Hmac::new_from_slice(b"pepper").unwrap(),
+        }
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    fn ip(addr: &str) -> Result<Client, ()> {
+        Ok(Client::Ip(addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn forwarded_addresses_need_a_trusted_peer() {
+        let identifier = identifier();
+        let headers = forwarded_for("1.1.1.1, 2.2.2.2, 10.0.0.2");
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let stranger = Some("3.3.3.3".parse().unwrap());
+
+        assert_eq!(identifier.identify(proxy, &headers), ip("2.2.2.2"));
+        assert_eq!(identifier.identify(stranger, &headers), ip("3.3.3.3"));
+        assert_eq!(identifier.identify(None, &headers), Ok(Client::Unknown));
+        assert_eq!(
+            identifier.identify(proxy, &forwarded_for("garbage")),
+            ip("10.0.0.1")
+        );
+    }
+
+    const BUCKET: Algorithm = Algorithm::TokenBucket {
+        capacity: 5,
+        refill: 2,
+        interval: Duration::from_secs(1),
+    };
+    const WINDOW: Algorithm = Algorithm::SlidingWindow {
+        limit: 3,
+        window: Duration::from_secs(10),
+    };
+
+    fn at(millis: u64) -> Timestamp {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn token_bucket_refills_whole_intervals() {
+        let mut state = LimiterState::new(&BUCKET, at(0));
+        assert!(state.try_acquire(&BUCKET, 4, at(0)));
+        assert!(!state.try_acquire(&BUCKET, 2, at(0)));
+
+        let status = state.status(&BUCKET, 2, at(0));
+        assert_eq!(status.remaining, 1);
+        assert_eq!(status.retry_after, at(1000));
+        assert_eq!(status.reset, at(2000));
+
+        // A partial interval is neither credited nor lost.
+        assert!(state.try_acquire(&BUCKET, 3, at(1500)));
+        assert!(state.try_acquire(&BUCKET, 2, at(2000)));
+        assert_eq!(state.status(&BUCKET, 1, at(2000)).remaining, 0);
+
+        // Refills stop at capacity.
+        state.update(&BUCKET, at(60_000));
+        assert_eq!(state.status(&BUCKET, 1, at(60_000)).remaining, 5);
+    }
+
+    #[test]
+    fn sliding_window_forgets_old_hits() {
+        let mut state = LimiterState::new(&WINDOW, at(0));
+        assert!(state.try_acquire(&WINDOW, 1, at(0)));
+        assert!(state.try_acquire(&WINDOW, 2, at(4000)));
+        assert!(!state.try_acquire(&WINDOW, 1, at(9999)));
+
+        let status = state.status(&WINDOW, 1, at(9999));
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.retry_after, at(1));
+        assert_eq!(status.reset, at(4001));
+
+        assert!(state.try_acquire(&WINDOW, 1, at(10_000)));
+        assert!(!state.try_acquire(&WINDOW, 1, at(10_000)));
+        assert!(state.try_acquire(&WINDOW, 2, at(14_000)));
+    }
+
+    #[test]
+    fn extreme_limits_saturate() {
+        let bucket = Algorithm::TokenBucket {
+            capacity: usize::MAX,
+            refill: usize::MAX / 2,
+            interval: Duration::from_nanos(1),
+        };
+        let mut state = LimiterState::new(&bucket, at(0));
+        assert!(state.try_acquire(&bucket, usize::MAX, at(0)));
+        assert!(state.try_acquire(&bucket, usize::MAX, Duration::MAX));
+        let status = state.status(&bucket, usize::MAX, Duration::MAX);
+        assert_eq!(status.retry_after, Duration::from_nanos(3));
+
+        let slow = Algorithm::TokenBucket {
+            capacity: usize::MAX,
+            refill: 1,
+            interval: Duration::MAX,
+        };
+        let state = LimiterState::new(&slow, at(0));
+        assert_eq!(state.status(&slow, usize::MAX, at(0)).reset, Duration::ZERO);
+        let mut state = LimiterState::new(&slow, at(0));
+        assert!(state.try_acquire(&slow, usize::MAX - 1, at(0)));
+        assert_eq!(state.status(&slow, 3, at(0)).retry_after, Duration::MAX);
+
+        let mut state = LimiterState::new(&WINDOW, at(0));
+        assert!(!state.try_acquire(&WINDOW, usize::MAX, at(0)));
+        let status = state.status(&WINDOW, usize::MAX, at(0));
+        assert_eq!(status.retry_after, Duration::ZERO);
+    }
+
+    #[test]
+    fn released_permits_can_be_taken_again() {
+        let mut state = LimiterState::new(&BUCKET, at(0));
+        assert!(state.try_acquire(&BUCKET, 5, at(0)));
+        state.release(&BUCKET, 3, at(0));
+        assert!(state.try_acquire(&BUCKET, 3, at(0)));
+        state.release(&BUCKET, 100, at(0));
+        assert_eq!(state.status(&BUCKET, 1, at(0)).remaining, 5);
+
+        let mut state = LimiterState::new(&WINDOW, at(0));
+        assert!(state.try_acquire(&WINDOW, 1, at(0)));
+        assert!(state.try_acquire(&WINDOW, 2, at(5000)));
+        state.release(&WINDOW, 2, at(5000));
+        assert_eq!(state.status(&WINDOW, 1, at(5000)).reset, at(5000));
+        assert!(state.try_acquire(&WINDOW, 2, at(5000)));
+    }
+
+    #[test]
+    fn state_follows_a_changed_algorithm() {
+        let mut state = LimiterState::new(&WINDOW, at(0));
+        assert!(state.try_acquire(&WINDOW, 3, at(0)));
+        assert!(state.try_acquire(&BUCKET, 5, at(0)));
+        assert!(!state.try_acquire(&BUCKET, 1, at(0)));
+    }
+
+    #[test]
+    fn limiters_are_restored_after_their_recovery_time() {
+        assert_eq!(BUCKET.recovery_time(), Duration::from_secs(3));
+        assert_eq!(WINDOW.recovery_time(), Duration::from_secs(10));
+        for algorithm in [BUCKET, WINDOW] {
+            let mut state = LimiterState::new(&algorithm, at(0));
+            let max = algorithm.max_permits();
+            assert!(state.try_acquire(&algorithm, max, at(500)));
+            let restored = at(500) + algorithm.recovery_time();
+            assert!(!state.try_acquire(&algorithm, max, restored - at(1)));
+            assert!(state.try_acquire(&algorithm, max, restored));
+        }
+        let slow = Algorithm::TokenBucket {
+            capacity: 1_000_000,
+            refill: 1,
+            interval: Duration::from_secs(24 * 60 * 60),
+        };
+        assert!(slow.recovery_time() > EVICTION_INTERVAL);
+    }
+
+    #[test]
+    fn api_keys_must_be_known() {
+        let identifier = identifier();
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HEADER, "secret".parse().unwrap());
+        let client = identifier.identify(None, &headers).unwrap();
+        assert_eq!(client, identifier.api_key("secret"));
+        assert!(!client.to_string().contains("secret"));
+        assert_ne!(client, identifier.api_key("secret2"));
+        headers.insert(API_KEY_HEADER, "guess".parse().unwrap());
+        assert_eq!(identifier.identify(None, &headers), Err(()));
+    }
+}